@@ -0,0 +1,140 @@
+// Signed queries. The query text is sent as the 'signed' field of an
+// envelope, with either an HMAC-SHA256 made with a key shared by moi and moid,
+// or an Ed25519 signature made with the private key of an operator.
+use super::*;
+use toml;
+use hmac::{Hmac,Mac};
+use sha2::{Sha256,Digest};
+use ed25519_dalek::{SigningKey,VerifyingKey,Signature,Signer,Verifier};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// key files contain the key in hex, and relative paths are
+// relative to the directory of the config file
+pub fn read_key_file(dir: &Path, file: &str) -> BoxResult<Vec<u8>> {
+    let file = dir.join(file);
+    let key = from_hex(read_to_string(&file)?.trim())
+        .map_err(|e| io_error(&format!("key file {}: {}",file.display(),e)))?;
+    Ok(key)
+}
+
+// binds the contents of a copied file to its (signed) cp query
+pub fn file_hash(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+fn ed25519_key(key: &[u8], what: &str) -> io::Result<[u8; 32]> {
+    (key.len() == 32).or_then_err(|| format!("{} must be 32 bytes",what))?;
+    let mut res = [0; 32];
+    res.copy_from_slice(key);
+    Ok(res)
+}
+
+fn hmac(key: &[u8], text: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes any key size");
+    mac.update(text.as_bytes());
+    mac
+}
+
+// a new Ed25519 private key, and its public key (both in hex)
+pub fn generate_key() -> BoxResult<(String,String)> {
+    let seed = random_bytes(32)?;
+    let key = SigningKey::from_bytes(&ed25519_key(&seed,"seed")?);
+    Ok((to_hex(&seed),to_hex(key.verifying_key().as_bytes())))
+}
+
+pub enum QuerySigner {
+    Hmac(Vec<u8>),
+    Ed25519(String,SigningKey),
+}
+
+impl QuerySigner {
+    // from the [auth] section of the moi config: hmac_key, or
+    // signing_key (and the operator name, which defaults to the user)
+    pub fn new(auth: &toml::Value, dir: &Path, user: &str) -> BoxResult<QuerySigner> {
+        if let Some(file) = gets_opt(auth,"hmac_key")? {
+            Ok(QuerySigner::Hmac(read_key_file(dir,file)?))
+        } else
+        if let Some(file) = gets_opt(auth,"signing_key")? {
+            let key = ed25519_key(&read_key_file(dir,file)?,"signing key")?;
+            let operator = gets_opt(auth,"operator")?.unwrap_or(user);
+            Ok(QuerySigner::Ed25519(operator.into(),SigningKey::from_bytes(&key)))
+        } else {
+            err_io("[auth] needs hmac_key or signing_key")
+        }
+    }
+
+    pub fn sign(&self, text: &str) -> JsonValue {
+        match *self {
+            QuerySigner::Hmac(ref key) => object!{
+                "signed" => text,
+                "hmac" => to_hex(&hmac(key,text).finalize().into_bytes())
+            },
+            QuerySigner::Ed25519(ref operator, ref key) => object!{
+                "signed" => text,
+                "sig" => to_hex(&key.sign(text.as_bytes()).to_bytes()),
+                "by" => operator.as_str()
+            }
+        }
+    }
+}
+
+pub struct QueryVerifier {
+    hmac_key: Option<Vec<u8>>,
+    operators: HashMap<String,VerifyingKey>,
+}
+
+impl QueryVerifier {
+    // from the [auth] section of the moid config: hmac_key, and/or a table
+    // 'operators' of operator names and their public keys
+    pub fn new(auth: Option<&toml::Value>, dir: &Path) -> BoxResult<QueryVerifier> {
+        let mut res = QueryVerifier {
+            hmac_key: None,
+            operators: HashMap::new(),
+        };
+        if let Some(auth) = auth {
+            if let Some(file) = gets_opt(auth,"hmac_key")? {
+                res.hmac_key = Some(read_key_file(dir,file)?);
+            }
+            if let Some(operators) = auth.get("operators") {
+                for (name,key) in toml_string_map(operators)? {
+                    let key = ed25519_key(&from_hex(&key)?,&format!("public key of {}",name))?;
+                    let key = VerifyingKey::from_bytes(&key)
+                        .map_err(|e| io_error(&format!("public key of {}: {}",name,e)))?;
+                    res.operators.insert(name,key);
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    // once there is a key, every query must be signed
+    pub fn required(&self) -> bool {
+        self.hmac_key.is_some() || ! self.operators.is_empty()
+    }
+
+    // the query text, and the operator if it was signed by one
+    pub fn verify<'a>(&self, envelope: &'a JsonValue) -> BoxResult<(&'a str,Option<String>)> {
+        let text = envelope["signed"].as_str().or_err("query is not signed")?;
+        if let Some(sig) = envelope["hmac"].as_str() {
+            let key = self.hmac_key.as_ref().or_err("HMAC signatures are not accepted")?;
+            hmac(key,text).verify_slice(&from_hex(sig)?)
+                .map_err(|_| io_error("bad query signature"))?;
+            Ok((text,None))
+        } else
+        if let Some(sig) = envelope["sig"].as_str() {
+            let operator = envelope["by"].as_str().or_err("signed query has no operator")?;
+            let key = self.operators.get(operator)
+                .or_then_err(|| format!("unknown operator {}",operator))?;
+            let sig = from_hex(sig)?;
+            (sig.len() == 64).or_err("bad query signature")?;
+            let mut bytes = [0; 64];
+            bytes.copy_from_slice(&sig);
+            key.verify(text.as_bytes(),&Signature::from_bytes(&bytes))
+                .map_err(|_| io_error("bad query signature"))?;
+            Ok((text,Some(operator.to_string())))
+        } else {
+            err_io("query has no signature")
+        }
+    }
+}
@@ -4,7 +4,10 @@ use toml;
 use ansi_term::Colour::White;
 use std::fs;
 use std::path::Path;
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
 use toml_utils::*;
+use moi::auth;
 
 pub struct CommandHandler<'a> {
     flags: &'a Flags,
@@ -72,6 +75,20 @@ impl <'a> CommandHandler<'a> {
         Ok(())
     }
 
+    // a new operator key: the private key goes into the file,
+    // and the public key is printed for the moid [auth.operators] table
+    pub fn keygen(&self, args: &[String]) -> BoxResult<bool> {
+        (args.len() == 1).or_err("keygen: private-key-file")?;
+        let file = Path::new(&args[0]);
+        let (private,public) = auth::generate_key()?;
+        let mut f = fs::OpenOptions::new().create_new(true).write(true).mode(0o600)
+            .open(file)
+            .map_err(|e| io_error(&format!("cannot create {}: {}",file.display(),e)))?;
+        f.write_all(private.as_bytes())?;
+        println!("{}",public);
+        Ok(true)
+    }
+
     pub fn custom_commands(&self) -> BoxResult<bool>  {
         if let Some(config_cmds) = self.config.get("commands") {
             if config_cmds.is_table() {
@@ -1,4 +1,6 @@
 use lapp;
+use json;
+use json::JsonValue;
 use moi::*;
 use toml;
 use libc;
@@ -26,10 +28,20 @@ MOI (MQTT Orchestration Interface) - execute commands on remote devices
             KEY=VALUE  test for equality
             KEY=VAL#   test for values that start with given string
             KEY:VALUE  test whether value is in the array KEY
-            KEY.not.VALUE test for values not equal to VALUE
+            KEY@ADDR/BITS test whether address value is in the network
+            KEY!=VALUE test for values not equal to VALUE
+            KEY.not.VALUE same as KEY!=VALUE
+            @NAME      use the saved filter NAME from [filters]
+            (KEY may be dotted, like net.eth0.mtu)
   -g, --group (default none) for a predefined group
-  -n, --name (default none) for either address, name or group
+  -n, --name (default none) for either address, network, name or group
   -T, --timeout (default 500) timeout for accessing all devices
+  --ttl (default none) keys given to set expire after this time (e.g. 30s, 10m, 2h, 1d)
+  --lock-ttl (default none) a group lock expires after this time (default lock_ttl in config)
+  --label (default none) note kept with the query on remotes, like a ticket number
+  --dry-run remotes only say what they would do
+  --maintenance also for remotes in maintenance mode
+  --force unlock a group even if someone else holds the lock
   -j, --json  JSON output
   -v, --verbose tell us all about what's going on...
   -q, --quiet output only on error
@@ -45,11 +57,30 @@ MOI (MQTT Orchestration Interface) - execute commands on remote devices
         push-run file dest cmd: copy a file and run a command
         pull file dest: copy remote files to us
         run-pull cmd file dest: run a command and then copy the result
-        set key=value...:  set keys on remotes
-        seta key=value...: append values to array-valued keys
+        set key=value...:  set keys on remotes (key.subkey=value for nested objects,
+            key:=JSON for numbers, booleans, arrays and objects)
+        seta key=value...: append values to array-valued keys (key:=JSON also works)
+        secret key=value...: set keys which are kept encrypted on remotes and never shown
+        inc key[=N]...: add N (default 1) to numeric keys
+        dec key[=N]...: subtract N (default 1) from numeric keys
+        cas key expected new: set key only if it currently has the expected value
         group name: create a group from the set of responses
         remove-group: remove a named group from the set
         groups: show defined groups
+        filters: show saved filters and how many of the 'all' group match
+        history [key]: show recent changes to keys on remotes
+        events [keys]: show changes to keys on remotes as they happen (until interrupted)
+        validate: show keys on remotes which do not agree with the schema in the moid config
+        dump [prefix]: show all keys on remotes (or those starting with prefix)
+        diff [keys]: group remotes by the values of keys (default all keys) and show the odd ones out
+        snapshot name: save the keys on remotes as a named snapshot
+        restore name: restore the keys on remotes from a named snapshot
+        keygen file: create a private key for signing queries, and print the public key
+        rotate-psk: give a group a new TLS-PSK key, and check that they come back with it
+        maintenance on|off: remotes in maintenance mode ignore queries, except for ls,
+            this command and those given --maintenance
+        lock: lock a group, so other operators can't run multi-stage commands on it
+        unlock: release the lock on a group (--force if someone else holds it)
         ping:  like ls, but gives round-trip time in msec
         time:  like ls, but gives difference between this time and device time, in secs
   <args> (string...) additional arguments for commands
@@ -71,6 +102,12 @@ pub struct CommandArgs {
 pub struct Flags {
     pub filter_desc: String,
     pub group_name: String,
+    pub ttl: String,
+    pub lock_ttl: String,
+    pub label: String,
+    pub dry_run: bool,
+    pub maintenance: bool,
+    pub force: bool,
     pub name_or_group: String,
     pub config_file: PathBuf,
     pub moi_dir: PathBuf,
@@ -83,6 +120,7 @@ pub struct Flags {
     pub json: bool,
     pub cols: Vec<String>,
     pub use_colour: bool,
+    pub filters: StringMap,
 }
 
 impl Flags {
@@ -171,6 +209,12 @@ impl Flags {
         Ok((commands,Flags {
             filter_desc: args.get_string("filter"),
             group_name: args.get_string("group"),
+            ttl: args.get_string("ttl"),
+            lock_ttl: args.get_string("lock-ttl"),
+            label: args.get_string("label"),
+            dry_run: args.get_bool("dry-run"),
+            maintenance: args.get_bool("maintenance"),
+            force: args.get_bool("force"),
             name_or_group: args.get_string("name"),
             timeout: args.get_integer("timeout"),
             verbose: args.get_bool("verbose"),
@@ -183,26 +227,39 @@ impl Flags {
             json: args.get_bool("json"),
             cols: args.get_strings("cols"),
             use_colour: use_colour,
+            filters: HashMap::new(),
         }))
 
     }
 
     ///// creating queries out of command-line args //////
 
-    fn keyvalue_args(args: &[String],is_arr: bool) -> BoxResult<HashMap<String,String>> {
+    // KEY=VALUE always gives a string (except for null), KEY:=VALUE is parsed as JSON
+    fn keyvalue_args(args: &[String],is_arr: bool) -> BoxResult<JsonValue> {
         use strutil::split_at_delim;
-        let mut map = HashMap::new();
+        let mut res = JsonValue::new_object();
         for s in args {
             let (mut k,v) = split_at_delim(s,"=")
                 .or_then_err(|| format!("{} is not a key-value pair",s))?;
+            let typed = k.ends_with(':');
+            if typed {
+                k = &k[0..k.len()-1];
+            }
             if is_arr && k.ends_with('+') { // allow '+=' for array append op
                 k = &k[0..k.len()-1];
             }
             KeyValue::valid_key(k)
                 .or_then_err(|| format!("{} is not a valid key name",k))?;
-            map.insert(k.to_string(),v.to_string());
+            res[k] = if typed {
+                json::parse(v).map_err(|e| io_error(&format!("value of {} is not valid JSON: {}",k,e)))?
+            } else
+            if v == "null" {
+                JsonValue::Null
+            } else {
+                v.into()
+            };
         }
-        Ok(map)
+        Ok(res)
     }
 
     fn remote_target_destination<'a>(&mut self, spec: &'a str) -> BoxResult<&'a str> {
@@ -218,12 +275,19 @@ impl Flags {
     // implement our commands as Query enum values
     fn construct_query(&mut self, cmd: &str, args: &[String], restricted: bool, config: &toml::Value) -> BoxResult<Query> {
         use strutil::strings;
-        if restricted && ! &["ls","time","ping"].contains(&cmd) {
+        if restricted && ! &["ls","time","ping","filters","history","dump","diff","validate"].contains(&cmd) {
             return err_io(&format!("{} is a restricted command. Use sudo",cmd));
         }
         match cmd {
             "ls" => {
-                Ok(Query::get(args,cmd.into()))
+                // ls also shows whether remotes are in maintenance mode
+                let mut args = args.to_vec();
+                args.push(MAINTENANCE_KEY.into());
+                Ok(Query::get(&args,cmd.into()))
+            },
+            "maintenance" => {
+                (args.len() == 1 && (args[0] == "on" || args[0] == "off")).or_err("maintenance: on|off")?;
+                Ok(Query::Maintenance(args[0] == "on"))
             },
             "time" => {
                 Ok(Query::get(&["time"],cmd.into()))
@@ -235,11 +299,88 @@ impl Flags {
                 (args.len() == 1).or_err("group: group-name")?;
                 Ok(Query::group(&args[0]))
             },
+            "inc" | "dec" => {
+                (args.len() > 0).or_then_err(|| format!("{}: key1[=amount] [key2[=amount] ...]",cmd))?;
+                let mut amounts = JsonValue::new_object();
+                for arg in args {
+                    let (k,n) = strutil::split_at_delim(arg,"=").unwrap_or((arg.as_str(),"1"));
+                    KeyValue::valid_key(k)
+                        .or_then_err(|| format!("{} is not a valid key name",k))?;
+                    let n: f64 = n.parse()
+                        .map_err(|_| io_error(&format!("{}: amount for {} must be a number",cmd,k)))?;
+                    amounts[k] = n.into();
+                }
+                Ok(if cmd=="inc" {Query::Inc(amounts)} else {Query::Dec(amounts)})
+            },
+            "cas" => {
+                (args.len() == 3).or_err("cas: key expected-value new-value")?;
+                KeyValue::valid_key(&args[0])
+                    .or_then_err(|| format!("{} is not a valid key name",args[0]))?;
+                let value = |v: &str| if v == "null" {JsonValue::Null} else {v.into()};
+                Ok(Query::Cas(args[0].clone(),value(&args[1]),value(&args[2])))
+            },
+            "snapshot" | "restore" => {
+                (args.len() == 1).or_then_err(|| format!("{}: snapshot-name",cmd))?;
+                let name = args[0].clone();
+                Ok(if cmd=="snapshot" {Query::Snapshot(name)} else {Query::Restore(name)})
+            },
+            "dump" => {
+                (args.len() <= 1).or_err("dump: [key-prefix]")?;
+                Ok(Query::Dump(args.get(0).cloned()))
+            },
+            "diff" => {
+                Ok(Query::Diff(args.to_vec()))
+            },
+            "validate" => {
+                (args.len() == 0).or_err("validate: takes no arguments")?;
+                Ok(Query::Validate)
+            },
+            "rotate-psk" => {
+                // (moi makes up the new key, and only does that for rotate-psk on its own)
+                err_io("rotate-psk: cannot be used in a chain or command alias")
+            },
+            "history" => {
+                (args.len() <= 1).or_err("history: [key]")?;
+                Ok(Query::History(args.get(0).cloned()))
+            },
+            "filters" => {
+                (self.filters.len() > 0).or_err("filters: no saved filters defined")?;
+                // counts are against the 'all' group unless told otherwise
+                if self.group_name == "none" && self.filter_desc == "none" {
+                    self.group_name = "all".into();
+                }
+                let mut names: Vec<_> = self.filters.keys().cloned().collect();
+                names.sort();
+                let mut saved = Vec::new();
+                for name in names {
+                    let desc = self.filters[&name].clone();
+                    let condition = Condition::from_description(&desc,&self.filters)?;
+                    saved.push(SavedFilter{name: name, desc: desc, condition: condition});
+                }
+                Ok(Query::Match(saved))
+            },
             "set" | "seta" => {
                 (args.len() > 0).or_then_err(|| format!("{}: key1=value1 [key2=value2 ...]",cmd))?;
                 let map = Flags::keyvalue_args(args,cmd=="seta")?;
+                if cmd=="set" && self.ttl != "none" {
+                    // the keys are set, and then told when to expire
+                    let secs = strutil::parse_duration(&self.ttl)?;
+                    let mut expiry = JsonValue::new_object();
+                    for (k,_) in map.entries() {
+                        expiry[k] = secs.into();
+                    }
+                    return Ok(Query::Chain(vec![Query::Set(map),Query::Expire(expiry)]));
+                }
                 Ok(if cmd=="set" {Query::Set(map)} else {Query::Seta(map)})
             },
+            "secret" => {
+                (args.len() > 0).or_err("secret: key1=value1 [key2=value2 ...]")?;
+                let map = Flags::keyvalue_args(args,false)?;
+                for (k,v) in map.entries() {
+                    v.is_string().or_then_err(|| format!("secret: value of {} must be a string",k))?;
+                }
+                Ok(Query::Secret(SecretValues(map)))
+            },
             "invoke" => {
                 (args.len() > 0).or_err("invoke: custom-command [key1=value1 ...]")?;
                 let name = args[0].clone();
@@ -367,6 +508,10 @@ impl Flags {
                 println!("alias set filter {}",self.filter_desc);
             }
         }
+        // aliases may bring their own saved filters
+        if let Some(filters) = t.get("filters") {
+            self.filters.extend(toml_string_map(filters)?);
+        }
         if let Some(group) = gets_opt(t,"group")? {
             self.group_name = group.into();
             if self.verbose {
@@ -376,6 +521,9 @@ impl Flags {
         if let Some(v) = t.get("quiet") {
             self.quiet = v.as_bool().or_err("alias: quiet must be a boolean")?;
         }
+        if let Some(v) = t.get("maintenance") {
+            self.maintenance = v.as_bool().or_err("alias: maintenance must be a boolean")?;
+        }
         if let Some(cols) = t.get("cols") {
             self.cols = toml_strings(cols.as_array().or_err("alias: cols must be an array")?)?;
         }
@@ -0,0 +1,157 @@
+// A cooperative lock on a group, so that two operators don't run their
+// deployments against the same devices at once. The lock is a retained
+// message on MOI/lock/GROUP, which says who holds it and until when.
+use moi::*;
+use mosquitto_client::Mosquitto;
+use json;
+use json::JsonValue;
+use std::time::{Duration,Instant};
+
+const LOCK_TOPIC: &str = "MOI/lock";
+
+// how long we listen for the retained lock message
+const LOCK_WAIT: u64 = 300;
+
+// the current lock on the topic, if any
+fn read_lock(m: &Mosquitto, topic: &str) -> BoxResult<Option<JsonValue>> {
+    let lock_resp = m.subscribe(topic,1)?;
+    let mut mc = m.callbacks(None);
+    mc.on_message(|latest,msg| {
+        if lock_resp.matches(&msg) {
+            // an empty message means there is no lock
+            *latest = json::parse(msg.text()).ok().filter(|l| l.is_object());
+        }
+    });
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(LOCK_WAIT) {
+        m.do_loop(50)?;
+    }
+    m.unsubscribe(topic)?;
+    // expired locks don't count
+    Ok(mc.data.take().filter(|l| l["expires"].as_i64().unwrap_or(0) > current_time_as_secs()))
+}
+
+fn describe(lock: &JsonValue) -> String {
+    let mut res = format!("{} until {}",lock["owner"],secs_as_str(lock["expires"].as_i64().unwrap_or(0)));
+    if let Some(label) = lock["label"].as_str() {
+        res += &format!(" ({})",label);
+    }
+    res
+}
+
+// publishes our lock (replacing any lock of our own), and returns its token
+fn publish_lock(m: &Mosquitto, group: &str, topic: &str, owner: &str, label: &str, ttl: i64) -> BoxResult<String> {
+    let token = to_hex(&random_bytes(8)?);
+    let now = current_time_as_secs();
+    let mut lock = object!{
+        "owner" => owner,
+        "time" => now,
+        "expires" => now + ttl,
+        "token" => token.as_str()
+    };
+    if label != "none" {
+        lock["label"] = label.into();
+    }
+    m.publish(topic,lock.to_string().as_bytes(),1,true)?;
+    // someone else may have been doing the same thing at the same time:
+    // the broker keeps the last lock, which wins
+    match read_lock(m,topic)? {
+        Some(ref lock) if lock["token"] == token.as_str() => {},
+        Some(ref lock) => return err_io(&format!("group {} was just locked by {}",group,describe(lock))),
+        None => return err_io(&format!("group {}: could not lock",group)),
+    }
+    info!("locked group {} for {}s",group,ttl);
+    Ok(token)
+}
+
+// released when dropped, so an error doesn't leave the group locked
+pub struct FleetLock {
+    m: Mosquitto,
+    group: String,
+    topic: String,
+    token: String,
+}
+
+impl FleetLock {
+    // Ok(None) if the owner already holds the lock, e.g. from 'moi lock'
+    pub fn acquire(m: &Mosquitto, group: &str, owner: &str, label: &str, ttl: i64) -> BoxResult<Option<FleetLock>> {
+        let topic = format!("{}/{}",LOCK_TOPIC,group);
+        if let Some(lock) = read_lock(m,&topic)? {
+            if lock["owner"] == owner {
+                return Ok(None);
+            }
+            return err_io(&format!("group {} is locked by {}",group,describe(&lock)));
+        }
+        let token = publish_lock(m,group,&topic,owner,label,ttl)?;
+        Ok(Some(FleetLock {
+            m: m.clone(),
+            group: group.into(),
+            topic: topic,
+            token: token,
+        }))
+    }
+
+    // only if the lock is still ours - it may have expired and been taken
+    fn release(&self) -> BoxResult<()> {
+        let m = &self.m;
+        match read_lock(m,&self.topic)? {
+            Some(ref lock) if lock["token"] == self.token.as_str() => {
+                m.publish(&self.topic,b"",1,true)?;
+                m.do_loop(50)?; // ensure it's actually published
+                info!("unlocked group {}",self.group);
+            },
+            _ => warn!("lock on group {} had expired",self.group)
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FleetLock {
+    fn drop(&mut self) {
+        // we may already have disconnected
+        let res = self.release().or_else(|_| {
+            self.m.reconnect()?;
+            let res = self.release();
+            self.m.disconnect()?;
+            res
+        });
+        if let Err(e) = res {
+            error!("cannot release lock on group {}: {}",self.group,e);
+        }
+    }
+}
+
+// 'moi lock' holds the lock until 'moi unlock' (or it expires)
+pub fn lock_command(m: &Mosquitto, group: &str, owner: &str, label: &str, ttl: i64) -> BoxResult<bool> {
+    let topic = format!("{}/{}",LOCK_TOPIC,group);
+    if let Some(lock) = read_lock(m,&topic)? {
+        if lock["owner"] != owner {
+            return err_io(&format!("group {} is locked by {}",group,describe(&lock)));
+        }
+    }
+    // our own lock is simply renewed. (Not a FleetLock, which would
+    // release the lock again as soon as we're done)
+    publish_lock(m,group,&topic,owner,label,ttl)?;
+    println!("locked group {} for {}s",group,ttl);
+    Ok(true)
+}
+
+pub fn unlock_command(m: &Mosquitto, group: &str, owner: &str, force: bool) -> BoxResult<bool> {
+    let topic = format!("{}/{}",LOCK_TOPIC,group);
+    match read_lock(m,&topic)? {
+        Some(ref lock) if lock["owner"] == owner || force => {
+            m.publish(&topic,b"",1,true)?;
+            m.do_loop(50)?;
+            if lock["owner"] != owner {
+                warn!("removed lock held by {}",describe(lock));
+            }
+            println!("unlocked group {}",group);
+            Ok(true)
+        },
+        Some(ref lock) => err_io(&format!("group {} is locked by {} (use --force)",group,describe(lock))),
+        None => {
+            println!("group {} is not locked",group);
+            Ok(true)
+        }
+    }
+}
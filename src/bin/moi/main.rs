@@ -15,10 +15,13 @@ mod strutil;
 mod query;
 mod flags;
 mod commands;
+mod psk;
+mod fleet_lock;
 // mod output;
 
 use moi::*;
 use moi::toml_utils::*;
+use moi::auth::QuerySigner;
 use query::*;
 
 use mosquitto_client::Mosquitto;
@@ -28,8 +31,8 @@ use Colour::{Red,Yellow,White};
 
 use std::path::{Path,PathBuf};
 use std::time::Duration;
-use std::collections::HashMap;
-use std::{fs,io,thread,process};
+use std::collections::{HashMap,BTreeMap,BTreeSet};
+use std::{fs,io,thread,process,env};
 use std::io::prelude::*;
 use std::error::Error;
 use std::cell::Cell;
@@ -44,6 +47,10 @@ const PROCESS_RESULT_TOPIC: &str = "MOI/result/process";
 const FILE_TOPIC_PREFIX: &str = "MOI/file";
 const TIMEOUT_TOPIC: &str = "MOI/pvt/timeout";
 const PROCESS_FETCH_TOPIC: &str = "MOI/fetch/";
+const EVENT_TOPIC: &str = "MOI/event";
+
+// these are expected to differ between devices, so diff ignores them
+const PER_DEVICE_KEYS: &[&str] = &["addr","name","tmp"];
 
 pub fn json_out(cmd: &str, ok: bool, addr: &str, name: &str, j: JsonValue, cols: &[&str]) {
     print!("{{\"cmd\":{:?},\"ok\":{},\"addr\":{:?},\"name\":{:?}",cmd, ok, addr, name);
@@ -82,8 +89,13 @@ struct MessageData {
     finis: bool,
     seq: u8,
     flags: flags::Flags,
-    no_groups: Cell<bool>
-
+    no_groups: Cell<bool>,
+    match_counts: Vec<usize>,
+    from: String,
+    host: String,
+    signer: Option<QuerySigner>,
+    // (device,keys) collected by diff
+    diff_results: Vec<(String,JsonValue)>,
 }
 
 impl MessageData {
@@ -103,6 +115,11 @@ impl MessageData {
             seq: 0,
             flags: flags,
             no_groups: Cell::new(false),
+            match_counts: Vec::new(),
+            diff_results: Vec::new(),
+            from: local_user(),
+            host: local_host(),
+            signer: None,
         }
     }
 
@@ -240,11 +257,29 @@ impl MessageData {
             "seq" => self.seq,
             "which" => self.filter.to_json(),
             "what" => q,
+            // who is asking: the user, and where from
+            "from" => self.from.as_str(),
+            "host" => self.host.as_str(),
+            // so moid can refuse stale or replayed queries
+            "time" => current_time_as_secs(),
+            "nonce" => to_hex(&random_bytes(8)?),
         };
         if let Some(ref name) = self.maybe_group {
             q_json["group"] = name.as_str().into();
         }
-        let payload = q_json.to_string();
+        if self.flags.label != "none" {
+            q_json["label"] = self.flags.label.as_str().into();
+        }
+        if self.flags.dry_run {
+            q_json["dry_run"] = true.into();
+        }
+        if self.flags.maintenance || self.current_query().for_maintenance() {
+            q_json["maintenance"] = true.into();
+        }
+        let mut payload = q_json.to_string();
+        if let Some(ref signer) = self.signer {
+            payload = signer.sign(&payload).to_string();
+        }
         if self.flags.verbose {
             println!("sent {}",payload);
         }
@@ -328,7 +363,29 @@ impl MessageData {
     }
 
 
+    // remotes tell us what they would have done
+    fn handle_dry_run(&mut self, id: String, resp: JsonValue) {
+        let name = self.lookup_name(&id);
+        // a chain says what each part would do (parts which only look answer as usual)
+        let lines = if resp.is_array() && resp.members().any(|r| r.is_string()) {
+            resp.members().filter(|r| r.is_string()).cloned().collect()
+        } else {
+            vec![resp]
+        };
+        for line in lines {
+            if ! self.flags.json {
+                println!("{}\t{}\t{}",self.bold(&id,White),self.bold(&name,White),line);
+            } else {
+                json_out(&self.current_command().command,true,&id,&name,array![line],&["dry-run"]);
+            }
+        }
+        self.response(id,true,true);
+    }
+
     fn handle_response(&mut self, id: String, mut resp: JsonValue) {
+        if self.flags.dry_run && ! self.query[self.seq as usize].is_looking() {
+            return self.handle_dry_run(id,resp);
+        }
         let mut ok = Some(true);
         let mut handled = false;
         let use_colour = self.flags.use_colour;
@@ -346,6 +403,8 @@ impl MessageData {
                 match command.as_str() {
                     "ls" =>  {
                         if ! self.flags.json {
+                            // the last value says whether the remote is in maintenance mode
+                            let maintenance = resp.array_remove(resp.len()-1) == true;
                             // Ugly. It will get Better...
                             let n = resp.len();
                             for idx in 0..n {
@@ -363,6 +422,9 @@ impl MessageData {
                                     print!("\t");
                                 }
                             }
+                            if maintenance {
+                                print!("\t{}",self.bold("maintenance",Yellow));
+                            }
                             println!();
                         } else {
                             // the result is _guaranteed_ to contain at least the address and the name
@@ -404,6 +466,98 @@ impl MessageData {
                 let name = self.lookup_name(&id);
                 println!("{}\t{}\t{}",id,name,resp);
             },
+            Query::Inc(_) | Query::Dec(_) => {
+                // the new values of the counters
+                let name = self.lookup_name(&id);
+                if ! self.flags.json {
+                    let values: Vec<_> = resp.entries().map(|(k,v)| format!("{}={}",k,v)).collect();
+                    println!("{}\t{}\t{}",self.bold(&id,White),self.bold(&name,White),values.join("\t"));
+                } else {
+                    let keys: Vec<_> = resp.entries().map(|(k,_)| k).collect();
+                    let mut values = JsonValue::new_array();
+                    for (_,v) in resp.entries() {
+                        values.push(v.clone()).unwrap();
+                    }
+                    json_out(&self.current_command().command,true,&id,&name,values,&keys);
+                }
+            },
+            Query::Dump(_) => {
+                let name = self.lookup_name(&id);
+                let mut keys: Vec<_> = resp.entries().map(|(k,_)| k).collect();
+                keys.sort();
+                if ! self.flags.json {
+                    for key in keys {
+                        println!("{}\t{}\t{}\t{}",self.bold(&id,White),self.bold(&name,White),key,resp[key]);
+                    }
+                } else {
+                    let mut values = JsonValue::new_array();
+                    for key in keys.iter() {
+                        values.push(resp[*key].clone()).unwrap();
+                    }
+                    json_out("dump",true,&id,&name,values,&keys);
+                }
+            },
+            Query::Diff(ref keys) => {
+                let name = self.lookup_name(&id);
+                let device = if name == "<unknown>" {id.clone()} else {name};
+                // with explicit keys, values come back as an array from get
+                let values = if keys.is_empty() {
+                    resp
+                } else {
+                    let mut values = JsonValue::new_object();
+                    for (key,v) in keys.iter().zip(resp.members()) {
+                        values[key.as_str()] = v.clone();
+                    }
+                    values
+                };
+                self.diff_results.push((device,values));
+            },
+            Query::Validate => {
+                let name = self.lookup_name(&id);
+                if ! self.flags.json {
+                    for problem in resp.members() {
+                        println!("{}\t{}\t{}\t{}\t{}",self.bold(&id,White),self.bold(&name,White),
+                            problem["key"],problem["value"],problem["problem"]);
+                    }
+                    if resp.is_empty() && ! self.flags.quiet {
+                        println!("{}\t{}\tok",self.bold(&id,White),self.bold(&name,White));
+                    }
+                } else {
+                    for problem in resp.members() {
+                        let cols = array![problem["key"].clone(),problem["value"].clone(),problem["problem"].clone()];
+                        json_out("validate",true,&id,&name,cols,&["key","value","problem"]);
+                    }
+                }
+                // so that moi fails if any remote has problems
+                ok = Some(resp.is_empty());
+            },
+            Query::Restore(_) => {
+                // keys which the remote would not let us put back
+                let name = self.lookup_name(&id);
+                for skip in resp.members() {
+                    if ! self.flags.json {
+                        println!("{}\t{}\tskipped {}\t{}",self.bold(&id,White),self.bold(&name,White),
+                            skip["key"],skip["problem"]);
+                    } else {
+                        let cols = array![skip["key"].clone(),skip["problem"].clone()];
+                        json_out("restore",false,&id,&name,cols,&["key","problem"]);
+                    }
+                }
+                ok = Some(resp.members().len() == 0);
+            },
+            Query::History(_) => {
+                let name = self.lookup_name(&id);
+                for change in resp.members() {
+                    let time = secs_as_str(change["time"].as_i64().unwrap_or(0));
+                    if ! self.flags.json {
+                        println!("{}\t{}\t{}\t{}\t{} -> {}\t{}",self.bold(&id,White),self.bold(&name,White),
+                            time,change["key"],change["old"],change["new"],change["client"]);
+                    } else {
+                        let cols = array![time.as_str(),change["key"].clone(),change["old"].clone(),change["new"].clone(),change["client"].clone()];
+                        json_out("history",true,&id,&name,cols,&["time","key","old","new","client"]);
+                    }
+                }
+            },
             Query::Group(_,_) => {
                 // a Get operation for collecting group members
                 let get = &resp[0];
@@ -413,6 +567,15 @@ impl MessageData {
                 ok = Some(self.handle_run_launch(&id,resp));
                 handled = true;
             },
+            Query::Match(ref filters) => {
+                // one boolean per saved filter
+                self.match_counts.resize(filters.len(),0);
+                for (idx,matched) in resp.members().enumerate() {
+                    if matched.as_bool() == Some(true) {
+                        self.match_counts[idx] += 1;
+                    }
+                }
+            },
             Query::Fetch(_) => {
                 // note: not currently used...
                 // contents coming over as MOI/fetch/{seq}/{addr}/{name}
@@ -442,6 +605,33 @@ impl MessageData {
         if self.no_groups.get() {
             warn!("all group not defined yet: say 'moi group all'");
         }
+        let grouping = match *self.current_query() {
+            Query::Group(_,_) => true,
+            _ => false
+        };
+        if self.flags.dry_run && grouping { // the group is not made
+            return Ok(self.responses.values().all(|&ok| ok));
+        }
+        // diff and match report on the responses, but with a group
+        // we must still say which members never answered
+        if let Query::Diff(ref keys) = *self.current_query() {
+            self.show_diff(keys);
+        } else
+        if let Query::Match(ref filters) = *self.current_query() {
+            let total = if self.maybe_group.is_some() {self.group.len()} else {self.responses.len()};
+            for (idx,f) in filters.iter().enumerate() {
+                let count = self.match_counts.get(idx).cloned().unwrap_or(0);
+                if ! self.flags.json {
+                    println!("{}\t{}/{}\t{}",self.bold(&f.name,White),count,total,f.desc);
+                } else {
+                    let res = object!{
+                        "cmd" => "filters", "name" => f.name.as_str(), "filter" => f.desc.as_str(),
+                        "matches" => count, "total" => total
+                    };
+                    println!("{}",res);
+                }
+            }
+        }
         Ok(if let Query::Group(ref name, _) = *self.current_query() {
             // the group command collects group members
             // which we then persist to file
@@ -457,12 +647,12 @@ impl MessageData {
                 }
             }
             let jg = to_jobject(&self.group);
-            { // NLL !
+            // another moi may have changed the store since we read it
+            store.update(|store| {
                 let groups = store.values.entry("groups".to_string())
                     .or_insert_with(|| JsonValue::new_object());
                 groups[name] = jg;
-            }
-            store.write()?;
+            })?;
             true
         } else
         if let Some(ref group_name) = self.maybe_group {
@@ -494,11 +684,54 @@ impl MessageData {
         })
     }
 
+    // for each key, group the devices by value. The biggest group
+    // is the majority, and everyone else is an outlier
+    fn show_diff(&self, keys: &[String]) {
+        let keys: Vec<String> = if keys.is_empty() {
+            let mut all = BTreeSet::new();
+            for &(_,ref values) in self.diff_results.iter() {
+                for (k,_) in values.entries() {
+                    if ! PER_DEVICE_KEYS.contains(&k) {
+                        all.insert(k.to_string());
+                    }
+                }
+            }
+            all.into_iter().collect()
+        } else {
+            keys.to_vec()
+        };
+        for key in keys.iter() {
+            let mut by_value: BTreeMap<String,Vec<&str>> = BTreeMap::new();
+            for &(ref device,ref values) in self.diff_results.iter() {
+                let value = values[key.as_str()].dump();
+                by_value.entry(value).or_insert_with(Vec::new).push(device.as_str());
+            }
+            let mut groups: Vec<_> = by_value.into_iter().collect();
+            groups.sort_by(|a,b| b.1.len().cmp(&a.1.len()));
+            for (idx,&(ref value,ref devices)) in groups.iter().enumerate() {
+                let outlier = idx > 0;
+                if ! self.flags.json {
+                    let value = if outlier {self.bold(value,Red)} else {ANSIString::from(value.as_str())};
+                    println!("{}\t{}\t{}\t{}",self.bold(key,White),value,devices.len(),devices.join(" "));
+                } else {
+                    let res = object!{
+                        "cmd" => "diff", "key" => key.as_str(), "value" => json::parse(value).unwrap(),
+                        "count" => devices.len(), "devices" => devices.clone(), "outlier" => outlier
+                    };
+                    println!("{}",res);
+                }
+            }
+        }
+    }
+
     fn process_flags(&mut self, store: &Config) -> BoxResult<()> {
         // --name: this can be an address, name or group!
         if self.flags.name_or_group != "none" {
             if strutil::is_ipv4(&self.flags.name_or_group) {
                 self.flags.filter_desc = format!("addr={}",self.flags.name_or_group);
+            } else
+            if strutil::is_cidr(&self.flags.name_or_group) {
+                self.flags.filter_desc = format!("addr@{}",self.flags.name_or_group);
             } else {  // a name?
                 if let Ok(addr) = self.lookup_addr(&self.flags.name_or_group) {
                     self.flags.filter_desc = format!("addr={}",addr);
@@ -532,11 +765,91 @@ fn lookup_group<'a>(store: &'a Config, group_name: &str) -> io::Result<&'a JsonV
     Ok(jgroup)
 }
 
+// 'moi events [keys]' prints changes to remote stores as they happen.
+// They can be restricted to keys (and their subkeys), and to remotes
+// with --name or --group
+fn watch_events(m: &Mosquitto, flags: &flags::Flags, store: &Config, keys: &[String]) -> BoxResult<bool> {
+    (flags.filter_desc == "none").or_err("events: use --name or --group, not --filter")?;
+    let all = store.values.get("groups").map(|g| g["all"].clone()).unwrap_or(JsonValue::Null);
+    let addrs: Option<Vec<String>> = if flags.name_or_group != "none" {
+        let name = flags.name_or_group.as_str();
+        if strutil::is_ipv4(name) {
+            Some(vec![name.to_string()])
+        } else
+        if let Some((addr,_)) = all.entries().find(|&(_,n)| n == name) {
+            Some(vec![addr.to_string()])
+        } else {
+            Some(lookup_group(store,name)?.entries().map(|(a,_)| a.to_string()).collect())
+        }
+    } else
+    if flags.group_name != "none" {
+        Some(lookup_group(store,&flags.group_name)?.entries().map(|(a,_)| a.to_string()).collect())
+    } else {
+        None
+    };
+    for key in keys {
+        KeyValue::valid_key(key).or_then_err(|| format!("{} is not a valid key name",key))?;
+    }
+
+    let events = m.subscribe(&format!("{}/#",EVENT_TOPIC),1)?;
+    let json = flags.json;
+    let use_colour = flags.use_colour;
+    let bold = |s: &str| if use_colour {White.bold().paint(s.to_string())} else {Style::new().paint(s.to_string())};
+    let keys = keys.to_vec();
+    let mut mc = m.callbacks(());
+    mc.on_message(|_,msg| {
+        if ! events.matches(&msg) {
+            return;
+        }
+        let event = match json::parse(msg.text()) {
+            Ok(event) => event,
+            Err(e) => {
+                error!("bad event on {}: {}",msg.topic(),e);
+                return;
+            }
+        };
+        let addr = event["addr"].as_str().unwrap_or("");
+        let key = event["key"].as_str().unwrap_or("");
+        if let Some(ref addrs) = addrs {
+            if ! addrs.iter().any(|a| a == addr) {
+                return;
+            }
+        }
+        let wanted = keys.is_empty() || keys.iter().any(|k| key == k || key.starts_with(&format!("{}.",k)));
+        if ! wanted {
+            return;
+        }
+        let name = event["name"].as_str().unwrap_or("");
+        let time = secs_as_str(event["time"].as_i64().unwrap_or(0));
+        if ! json {
+            println!("{}\t{}\t{}\t{}\t{} -> {}",time,bold(addr),bold(name),key,event["old"],event["new"]);
+        } else {
+            let cols = array![time.as_str(),key,event["old"].clone(),event["new"].clone()];
+            json_out("events",true,addr,name,cols,&["time","key","old","new"]);
+        }
+    });
+    // runs until interrupted
+    m.loop_until_disconnect(-1)?;
+    Ok(true)
+}
+
+// who is running moi, and where
+fn local_user() -> String {
+    env::var("SUDO_USER").or_else(|_| env::var("USER")).unwrap_or("<unknown>".into())
+}
+
+fn local_host() -> String {
+    run_shell_command("hostname",None,&[]).1
+}
+
 // our real error-returning main function.
 fn run() -> BoxResult<bool> {
     let (commands,mut flags) = flags::Flags::new()?;
     let toml: toml::Value = read_to_string(&flags.config_file)?.parse()?;
     let config = toml.get("config").or_err("No [config] section")?;
+    if let Some(filters) = toml.get("filters") {
+        flags.filters = toml_string_map(filters)?;
+    }
 
     let path: PathBuf = if let Some(log_file) = gets_opt(config,"log_file")? {
         log_file.into()
@@ -584,6 +897,9 @@ fn run() -> BoxResult<bool> {
             "commands" => {
                 return cmds.custom_commands();
             },
+            "keygen" => {
+                return cmds.keygen(&commands[0].arguments);
+            },
             "setup" => { // dummy command (MAY become required)
                 return Ok(true);
             },
@@ -591,7 +907,13 @@ fn run() -> BoxResult<bool> {
         }
     }
 
-    let m = mosquitto_setup("moi",&config,&toml,flags.moi_dir.join("certs"))?;
+    // several operators may be running moi at once
+    let mosq_name = format!("moi-{}-{}",local_host(),process::id());
+    let m = mosquitto_setup(&mosq_name,&config,&toml,flags.moi_dir.join("certs"))?;
+
+    if commands[0].command == "events" {
+        return watch_events(&m,&flags,&store,&commands[0].arguments);
+    }
 
     let query_resp = m.subscribe(QUERY_FILE_RESULT_TOPIC,1)?;
     let group_resp = m.subscribe(QUERY_GROUP_RESULT_TOPIC,1)?;
@@ -611,22 +933,61 @@ fn run() -> BoxResult<bool> {
     } else {
         gets_or(&config,"restricted","yes")? == "yes"
     };
-    let query = flags.construct_query_alias(&toml, &config, &commands, restricted)?;
+
+    // group locks are held by user@host
+    let owner = format!("{}@{}",local_user(),local_host());
+    let lock_ttl = if flags.lock_ttl != "none" {
+        strutil::parse_duration(&flags.lock_ttl)?
+    } else {
+        geti_or(&config,"lock_ttl",3600)? as i64
+    };
+    if commands[0].command == "lock" || commands[0].command == "unlock" {
+        (! restricted).or_then_err(|| format!("{} is a restricted command. Use sudo",commands[0].command))?;
+        let group = if flags.group_name != "none" {&flags.group_name} else {&flags.name_or_group};
+        (group != "none").or_then_err(|| format!("{}: needs --group",commands[0].command))?;
+        return if commands[0].command == "lock" {
+            fleet_lock::lock_command(&m,group,&owner,&flags.label,lock_ttl)
+        } else {
+            fleet_lock::unlock_command(&m,group,&owner,flags.force)
+        };
+    }
+
+    // the new key must work before we hand it out
+    let (rotation,query) = if commands.len() == 1 && commands[0].command == "rotate-psk" {
+        (! restricted).or_err("rotate-psk is a restricted command. Use sudo")?;
+        commands[0].arguments.is_empty().or_err("rotate-psk: takes no arguments")?;
+        (flags.group_name != "none").or_err("rotate-psk: needs --group")?;
+        let rotation = psk::PskRotation::prepare(&mosq_name,&config,&toml,flags.moi_dir.join("certs"))?;
+        let query = Query::Psk(PskKey{identity: rotation.identity.clone(), key: rotation.key.clone()});
+        (Some(rotation),query)
+    } else {
+        (None,flags.construct_query_alias(&toml, &config, &commands, restricted)?)
+    };
 
     // message data is managed by mosquitto on_message handler
     let mut message_data = MessageData::new(&m,flags,commands);
 
+    // queries are signed if there is an [auth] section
+    if let Some(auth) = toml.get("auth") {
+        let dir = message_data.flags.config_file.parent().unwrap_or(Path::new(".")).to_path_buf();
+        message_data.signer = Some(QuerySigner::new(auth,&dir,&message_data.from)?);
+    }
+
     message_data.all_group = match store.values.get("groups") {
         Some(groups) => {
             groups["all"].clone()
         },
         None => JsonValue::Null
     };
+    let multi_stage = match query {
+        Query::Actions(_) => true,
+        _ => false
+    };
     message_data.set_queries(query);
 
     message_data.process_flags(&store)?;
 
-    let filter = Condition::from_description(&message_data.flags.filter_desc);
+    let filter = Condition::from_description(&message_data.flags.filter_desc,&message_data.flags.filters)?;
     info!("filter {:?}",filter);
 
     // Queries only meant for one device cause a temporary group
@@ -641,6 +1002,16 @@ fn run() -> BoxResult<bool> {
         warn!("Warning: no group defined for wait! Setting timeout to {}ms",LAUNCH_TIMEOUT);
     }
 
+    // multi-stage commands on a group hold the group's lock while they run.
+    // It is taken once everything else is checked, but before our callbacks
+    // are set, since reading the lock needs callbacks of its own
+    let group = message_data.flags.group_name.clone();
+    let fleet_lock = if multi_stage && group != "none" && ! message_data.flags.dry_run {
+        fleet_lock::FleetLock::acquire(&m,&group,&owner,&message_data.flags.label,lock_ttl)?
+    } else {
+        None
+    };
+
     let timeout = timeout::Timeout::new_shared(message_data.flags.timeout);
 
     let msg_timeout = timeout.clone();
@@ -765,7 +1136,34 @@ fn run() -> BoxResult<bool> {
 
     m.loop_until_disconnect(-1)?;
 
-    let ok = mc.data.finish_off(&mut store)?;
+    // done with the group
+    drop(fleet_lock);
+
+    let mut ok = mc.data.finish_off(&mut store)?;
+
+    // (a dry run only checks that the broker accepts the new key)
+    if let Some(rotation) = rotation.filter(|_| ! mc.data.flags.dry_run) {
+        let data = &mc.data;
+        let switching = data.group.iter()
+            .filter(|&(addr,_)| data.responses.get(addr) == Some(&true))
+            .map(|(addr,name)| (addr.clone(),name.clone()))
+            .collect();
+        let wait = geti_or(&config,"psk_wait",30)? as u64;
+        let lost = rotation.wait_for_switch(switching,Duration::from_secs(wait))?;
+        let stragglers: Vec<_> = data.group.iter()
+            .filter(|&(addr,_)| data.responses.get(addr) != Some(&true))
+            .collect();
+        for &(addr,name) in &stragglers {
+            error!("{}\t{}\tstill on the old key",addr,name);
+        }
+        if lost.is_empty() && stragglers.is_empty() {
+            rotation.finish()?;
+            warn!("rotated to {}: the old identity can now be removed from the broker",rotation.identity);
+        } else {
+            warn!("run rotate-psk again to finish rotating to {}",rotation.identity);
+            ok = false;
+        }
+    }
 
     Ok(ok)
 }
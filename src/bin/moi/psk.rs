@@ -0,0 +1,107 @@
+// Rotating the TLS-PSK key. The new identity and key are kept in PSK_FILE.new
+// until the rotation is finished, so that running rotate-psk again reaches
+// any remotes which missed it the first time.
+use moi::*;
+use toml;
+use mosquitto_client::Mosquitto;
+use json;
+use std::collections::HashMap;
+use std::path::{Path,PathBuf};
+use std::time::{Duration,Instant};
+use std::os::unix::fs::OpenOptionsExt;
+use std::io::prelude::*;
+use std::fs;
+
+// remotes report here once they have tried the new key
+const PSK_TOPIC: &str = "MOI/psk";
+
+fn with_extension(file: &Path, ext: &str) -> PathBuf {
+    PathBuf::from(format!("{}.{}",file.display(),ext))
+}
+
+pub struct PskRotation {
+    pub identity: String,
+    pub key: String,
+    file: PathBuf,
+    new_file: PathBuf,
+    m: Mosquitto,
+}
+
+impl PskRotation {
+    // makes up a new identity and key (unless there is one from last time),
+    // and checks that the broker accepts them
+    pub fn prepare(name: &str, config: &toml::Value, toml: &toml::Value, cert_dir: PathBuf) -> BoxResult<PskRotation> {
+        toml.get("tls").is_none().or_err("rotate-psk: moi uses TLS certificates, not TLS-PSK")?;
+        let file = tls_psk_file(toml,&cert_dir)?.or_err("rotate-psk: moi has no [tls_psk] section")?;
+        let new_file = with_extension(&file,"new");
+        if ! new_file.exists() {
+            let identity = format!("moi-{}",current_time_as_secs());
+            let key = to_hex(&random_bytes(32)?);
+            let mut f = fs::OpenOptions::new().create_new(true).write(true).mode(0o600)
+                .open(&new_file)?;
+            write!(f,"{}:{}\n",identity,key)?;
+        }
+        let (identity,key) = read_psk_file(&new_file)?;
+        let m = mosquitto_psk_setup(&format!("{}-psk",name),config,toml,&identity,&key)
+            .map_err(|e| io_error(&format!(
+                "rotate-psk: broker does not accept the new key ({}).\n\
+                 Add this line to the broker's psk_file, keeping the old one, and try again:\n{}:{}",
+                e,identity,key)))?;
+        // before any remote can switch
+        m.subscribe(PSK_TOPIC,1)?;
+        Ok(PskRotation {
+            identity: identity,
+            key: key,
+            file: file,
+            new_file: new_file,
+            m: m,
+        })
+    }
+
+    // remotes which have the new key reconnect with it, and say whether
+    // the broker took it (if not, they go back to the old key)
+    pub fn wait_for_switch(&self, mut waiting: HashMap<String,String>, wait: Duration) -> BoxResult<HashMap<String,String>> {
+        if ! waiting.is_empty() {
+            println!("waiting for {} remotes to switch...",waiting.len());
+        }
+        let start = Instant::now();
+        let mut failed = HashMap::new();
+        {
+            let identity = self.identity.as_str();
+            let mut mc = self.m.callbacks((&mut waiting,&mut failed));
+            mc.on_message(|data,msg| {
+                let report = match json::parse(msg.text()) {
+                    Ok(report) => report,
+                    Err(_) => return
+                };
+                if report["identity"].as_str() != Some(identity) {
+                    return;
+                }
+                let addr = report["addr"].as_str().unwrap_or("");
+                if let Some(name) = data.0.remove(addr) {
+                    if report["ok"].as_bool() == Some(true) {
+                        println!("{}\t{}\tswitched",addr,name);
+                    } else {
+                        error!("{}\t{}\tstill on the old key: {}",addr,name,report["error"]);
+                        data.1.insert(addr.to_string(),name);
+                    }
+                }
+            });
+            while ! mc.data.0.is_empty() && start.elapsed() < wait {
+                self.m.do_loop(100)?;
+            }
+        }
+        for (addr,name) in &waiting {
+            error!("{}\t{}\tdid not say whether it switched (the old key is in psk_file.old)",addr,name);
+        }
+        waiting.extend(failed);
+        Ok(waiting)
+    }
+
+    // the new key becomes our key, and the old one is kept as PSK_FILE.old
+    pub fn finish(&self) -> BoxResult<()> {
+        fs::rename(&self.file,with_extension(&self.file,"old"))?;
+        fs::rename(&self.new_file,&self.file)?;
+        Ok(())
+    }
+}
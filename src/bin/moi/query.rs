@@ -7,11 +7,11 @@ use std::fs;
 use std::io;
 use std::io::prelude::*;
 
-//use moi::*;
+use moi::*;
 
 use strutil::split_at_delim;
 
-type StringMap = HashMap<String,String>;
+pub type StringMap = HashMap<String,String>;
 
 // currently we do not want the command API messing with
 // these special snowflakes
@@ -70,9 +70,13 @@ impl KeyValue {
         }
     }
 
+    // dotted keys like 'net.eth0.mtu' refer to fields of nested objects;
+    // it is the top-level key which must not be clobbered
     pub fn valid_key(key: &str) -> bool {
-        key.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') &&
-         ! DONT_CLOBBER.contains(&key)
+        let base = key.split('.').next().unwrap();
+        key.split('.').all(|part| ! part.is_empty() &&
+            part.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')) &&
+         ! DONT_CLOBBER.contains(&base)
     }
 }
 
@@ -83,6 +87,7 @@ pub enum Condition {
     Starts(KeyValue),
     Exists(String),
     Elem(KeyValue),
+    Cidr(KeyValue),
     Any(Vec<Condition>),
     All(Vec<Condition>),
     None
@@ -96,6 +101,7 @@ impl Condition {
             Condition::Starts(ref kv) => object!{"starts"=>kv.as_jarray()},
             Condition::Exists(ref key) => object!{"exists"=>array![key.as_str()]},
             Condition::Elem(ref kv) => object!{"elem"=>kv.as_jarray()},
+            Condition::Cidr(ref kv) => object!{"cidr"=>kv.as_jarray()},
             Condition::All(ref cc) => object!{"all" => Condition::jmap(cc)},
             Condition::Any(ref cc) => object!{"any" => Condition::jmap(cc)},
             Condition::None => JsonValue::Null
@@ -111,17 +117,34 @@ impl Condition {
         res
     }
 
-    pub fn from_description(txt: &str) -> Condition {
+    // '@NAME' refers to a saved filter, which may itself refer to other saved filters
+    pub fn from_description(txt: &str, filters: &StringMap) -> io::Result<Condition> {
+        Condition::parse(txt,filters,0)
+    }
+
+    fn parse(txt: &str, filters: &StringMap, depth: usize) -> io::Result<Condition> {
         if txt.starts_with("any ") || txt.starts_with("all ") {
             let any = txt.starts_with("any ");
             let txt = &txt[4..];
-            let condns: Vec<_> = txt.split_whitespace()
-                .map(|s| Condition::from_description(s)).collect();
-            return if any { Condition::Any(condns) } else { Condition::All(condns) };
+            let condns = txt.split_whitespace()
+                .map(|s| Condition::parse(s,filters,depth))
+                .collect::<io::Result<Vec<_>>>()?;
+            return Ok(if any { Condition::Any(condns) } else { Condition::All(condns) });
         }
         if txt == "none" {
-            return Condition::None;
+            return Ok(Condition::None);
+        }
+        if txt.starts_with('@') {
+            let name = &txt[1..];
+            (depth < 8).or_then_err(|| format!("saved filter {} is nested too deeply",name))?;
+            let desc = filters.get(name).or_then_err(|| format!("no saved filter called {}",name))?;
+            return Condition::parse(desc,filters,depth+1);
         }
+        // '!=' must be tried before '=', and both before '.not.',
+        // which is ambiguous with dotted keys
+        let condn = if let Some((k,v)) = split_at_delim(txt,"!=") {
+            Condition::NotEquals(KeyValue::new(k,v))
+        } else
         if let Some((k,v)) = split_at_delim(txt,"=") {
             let mut kv = KeyValue::new(k,v);
             if v.ends_with('#') {
@@ -134,11 +157,15 @@ impl Condition {
         if let Some((k,v)) = split_at_delim(txt,":") {
             Condition::Elem(KeyValue::new(k,v))
         } else
+        if let Some((k,v)) = split_at_delim(txt,"@") {
+            Condition::Cidr(KeyValue::new(k,v))
+        } else
         if let Some((k,v)) = split_at_delim(txt,".not.") {
             Condition::NotEquals(KeyValue::new(k,v))
         } else {
             Condition::Exists(txt.into())
-        }
+        };
+        Ok(condn)
     }
 
     pub fn unique_id(&self) -> Option<(String,bool)> {
@@ -162,6 +189,7 @@ pub struct CopyFile {
     pub dest: String,
     pub perms: Option<u32>,
     pub hash: Option<String>,
+    pub sha256: Option<String>,
 }
 
 use std::fmt;
@@ -174,6 +202,28 @@ impl fmt::Debug for CopyFile {
     }
 }
 
+// values for 'secret', which must not end up in the log
+pub struct SecretValues(pub JsonValue);
+
+impl fmt::Debug for SecretValues {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let keys: Vec<_> = self.0.entries().map(|(k,_)| k).collect();
+        write!(f, "SecretValues {{ keys: {:?} }}", keys)
+    }
+}
+
+// a new TLS-PSK identity and key - the key must not end up in the log either
+pub struct PskKey {
+    pub identity: String,
+    pub key: String,
+}
+
+impl fmt::Debug for PskKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PskKey {{ identity: {:?} }}", self.identity)
+    }
+}
+
 impl CopyFile {
     pub fn new(file: PathBuf, dest: &str) -> io::Result<CopyFile> {
         let filename = file.file_name().unwrap().to_str().unwrap().to_string();
@@ -185,6 +235,7 @@ impl CopyFile {
             dest: dest.into(),
             perms: Some(perms),
             hash: None,
+            sha256: None,
         })
     }
 
@@ -193,7 +244,8 @@ impl CopyFile {
         let mut bytes = Vec::new();
         f.read_to_end(&mut bytes)?;
         let digest = md5::compute(&bytes);
-        self.hash = Some(format!("{:x}",digest));
+        self.hash = Some(format!("{:x}",digest)); // for older moid
+        self.sha256 = Some(auth::file_hash(&bytes));
         self.bytes = bytes;
         Ok(())
     }
@@ -204,6 +256,7 @@ impl CopyFile {
             "dest" => s(&self.dest),
             "perms" => self.perms,
             "hash" => as_option(&self.hash),
+            "sha256" => as_option(&self.sha256),
         }
     }
 }
@@ -246,14 +299,23 @@ impl RunCommand {
     }
 }
 
+// a named filter from the [filters] table
+#[derive(Debug)]
+pub struct SavedFilter {
+    pub name: String,
+    pub desc: String,
+    pub condition: Condition,
+}
+
 use std::time::Instant;
 
 #[derive(Debug)]
 pub enum Query {
     Get(Vec<String>,String),
     Group(String,Box<Query>),
-    Set(StringMap),
-    Seta(StringMap),
+    Set(JsonValue),
+    Seta(JsonValue),
+    Secret(SecretValues),
     Rma(StringMap),
     Run(RunCommand),
     Launch(RunCommand),
@@ -264,10 +326,25 @@ pub enum Query {
     Ping(Instant),
     Chain(Vec<Query>),
     Actions(Vec<Query>),
-    Invoke(String,StringMap),
+    Invoke(String,JsonValue),
+    Match(Vec<SavedFilter>),
+    History(Option<String>),
+    Expire(JsonValue),
+    Inc(JsonValue),
+    Dec(JsonValue),
+    Cas(String,JsonValue,JsonValue),
+    Snapshot(String),
+    Restore(String),
+    Dump(Option<String>),
+    Diff(Vec<String>),
+    Validate,
+    Psk(PskKey),
+    Maintenance(bool),
     Wait,
 }
 
+pub const MAINTENANCE_KEY: &str = "maintenance";
+
 fn pair_map(name: &str, value: &str) -> StringMap {
     let mut map = HashMap::new();
     map.insert(name.to_string(),value.to_string());
@@ -304,7 +381,7 @@ impl Query {
             name.into(),
             Box::new(Query::Chain(vec![
                 Query::get(&["addr","name"],"group"),
-                Query::Seta(pair_map("groups",name))
+                Query::Seta(to_jobject(&pair_map("groups",name)))
             ]))
         )
     }
@@ -313,6 +390,26 @@ impl Query {
         Query::Rma(pair_map(name,value))
     }
 
+    // remotes in maintenance mode still answer these
+    pub fn for_maintenance(&self) -> bool {
+        match *self {
+            Query::Get(_,ref command) => command == "ls",
+            // or else a group made during maintenance would leave them out
+            Query::Group(_,_) => true,
+            Query::Maintenance(_) => true,
+            _ => false
+        }
+    }
+
+    // these change nothing, so a dry run answers them as usual
+    pub fn is_looking(&self) -> bool {
+        match *self {
+            Query::Get(_,_) | Query::Ping(_) | Query::Dump(_) | Query::Diff(_) | Query::Validate |
+            Query::History(_) | Query::Match(_) | Query::Wait => true,
+            _ => false
+        }
+    }
+
     pub fn is_wait(&self) -> bool {
         match *self {
             Query::Wait => true,
@@ -325,8 +422,9 @@ impl Query {
             Query::Get(ref vs,_) => object!{"get" => to_jarray(vs)},
             Query::Ping(_) => object!{"get" => array!["addr","name"]},
             Query::Group(_,ref chain) => chain.to_json(),
-            Query::Set(ref kvs) => object!{"set"=>to_jobject(kvs)},
-            Query::Seta(ref kvs) => object!{"seta"=>to_jobject(kvs)},
+            Query::Set(ref kvs) => object!{"set"=>kvs.clone()},
+            Query::Seta(ref kvs) => object!{"seta"=>kvs.clone()},
+            Query::Secret(ref kvs) => object!{"secret"=>kvs.0.clone()},
             Query::Rma(ref kvs) => object!{"rma"=>to_jobject(kvs)},
             Query::Run(ref r) => object!{"run" => r.to_json() },
             Query::Launch(ref r) => object!{"launch" => r.to_json()},
@@ -341,10 +439,38 @@ impl Query {
                 }
                 object!{"chain" => res}
             },
+            Query::Match(ref filters) => {
+                let mut res = JsonValue::new_array();
+                for f in filters {
+                    res.push(f.condition.to_json()).unwrap();
+                }
+                object!{"match" => res}
+            },
+            Query::History(ref key) => object!{"history" => as_option(key)},
+            Query::Expire(ref kvs) => object!{"expire" => kvs.clone()},
+            Query::Inc(ref kvs) => object!{"inc" => kvs.clone()},
+            Query::Dec(ref kvs) => object!{"dec" => kvs.clone()},
+            Query::Cas(ref key, ref expect, ref value) => object!{
+                "cas" => object!{"key" => key.as_str(), "expect" => expect.clone(), "value" => value.clone()}
+            },
+            Query::Dump(ref prefix) => object!{"dump" => as_option(prefix)},
+            // diffing all keys needs the whole store
+            Query::Diff(ref keys) => if keys.is_empty() {
+                object!{"dump" => JsonValue::Null}
+            } else {
+                object!{"get" => to_jarray(keys)}
+            },
+            Query::Snapshot(ref name) => object!{"snapshot" => name.as_str()},
+            Query::Restore(ref name) => object!{"restore" => name.as_str()},
+            Query::Validate => object!{"validate" => JsonValue::Null},
+            Query::Maintenance(on) => object!{"maintenance" => on},
+            Query::Psk(ref p) => object!{
+                "psk" => object!{"identity" => p.identity.as_str(), "key" => p.key.as_str()}
+            },
             Query::Wait => object!{"wait" => JsonValue::Null},
             Query::Invoke(ref op, ref kvs) => {
                 let mut res = JsonValue::new_object();
-                res[op] = to_jobject(kvs);
+                res[op] = kvs.clone();
                 res
             },
             Query::Actions(_) => panic!("used Actions directly!")
@@ -6,6 +6,29 @@ pub fn is_ipv4(addr: &str) -> bool {
     res.is_ok()
 }
 
+// network specification like 10.2.0.0/16
+pub fn is_cidr(spec: &str) -> bool {
+    if let Some((addr,bits)) = split_at_delim(spec,"/") {
+        is_ipv4(addr) && bits.parse::<u8>().map(|b| b <= 32).unwrap_or(false)
+    } else {
+        false
+    }
+}
+
+// durations like 30s, 10m, 2h or 1d; plain numbers are seconds
+pub fn parse_duration(spec: &str) -> BoxResult<i64> {
+    let (num,mult) = match spec.chars().last() {
+        Some('s') => (&spec[0..spec.len()-1], 1),
+        Some('m') => (&spec[0..spec.len()-1], 60),
+        Some('h') => (&spec[0..spec.len()-1], 60*60),
+        Some('d') => (&spec[0..spec.len()-1], 24*60*60),
+        _ => (spec, 1)
+    };
+    let num: i64 = num.parse()
+        .map_err(|_| io_error(&format!("bad duration '{}' (e.g. 30s, 10m, 2h, 1d)",spec)))?;
+    Ok(num*mult)
+}
+
 pub fn strings<T: ToString>(slice: &[T]) -> Vec<String> {
     slice.iter().map(|s| s.to_string()).collect()
 }
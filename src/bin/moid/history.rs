@@ -0,0 +1,72 @@
+// A bounded record of changes made to the store by queries
+use moi::*;
+use secrets::redact;
+use json;
+use json::JsonValue;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+pub struct History {
+    file: PathBuf,
+    size: usize,
+    entries: VecDeque<JsonValue>,
+}
+
+impl History {
+    pub fn new(file: PathBuf, size: usize) -> BoxResult<History> {
+        let mut entries = VecDeque::new();
+        if file.exists() {
+            let doc = json::parse(&read_to_string(&file)?)
+                .map_err(|e| io_error(&format!("history json: {}",e)))?;
+            for e in doc.members() {
+                entries.push_back(e.clone());
+            }
+        }
+        Ok(History {
+            file: file,
+            size: size,
+            entries: entries,
+        })
+    }
+
+    // failing to keep history is not a reason to fail the query.
+    // Secret values are only recorded as having changed
+    pub fn record(&mut self, key: &str, old: &JsonValue, new: &JsonValue, client: &str) {
+        if old == new {
+            return;
+        }
+        self.entries.push_back(object!{
+            "key" => key,
+            "old" => redact(old),
+            "new" => redact(new),
+            "time" => current_time_as_secs(),
+            "client" => client
+        });
+        while self.entries.len() > self.size {
+            self.entries.pop_front();
+        }
+        let mut arr = JsonValue::new_array();
+        for e in self.entries.iter() {
+            arr.push(e.clone()).unwrap();
+        }
+        if let Err(e) = write_all(&self.file,&arr.dump()) {
+            error!("history: {}",e);
+        }
+    }
+
+    // all changes, or only those to a key and its subkeys
+    pub fn changes(&self, key: Option<&str>) -> JsonValue {
+        let mut res = JsonValue::new_array();
+        for e in self.entries.iter() {
+            let ekey = as_str_always(&e["key"]);
+            let wanted = match key {
+                Some(key) => ekey == key || (ekey.starts_with(key) && ekey[key.len()..].starts_with('.')),
+                None => true
+            };
+            if wanted {
+                res.push(e.clone()).unwrap();
+            }
+        }
+        res
+    }
+}
@@ -6,9 +6,16 @@ extern crate toml;
 extern crate mosquitto_client;
 extern crate md5;
 extern crate libc;
+extern crate chacha20poly1305;
 
 mod plugin;
+mod history;
+mod secrets;
+mod policy;
 use plugin::Plugins;
+use history::History;
+use secrets::Secrets;
+use policy::Policy;
 
 const VERSION: &str = "0.1.6";
 
@@ -17,6 +24,7 @@ use json::JsonValue;
 
 use moi::*;
 use moi::toml_utils::*;
+use moi::auth::QueryVerifier;
 
 // we don't do Windows for now, sorry
 use std::os::unix::fs::OpenOptionsExt;
@@ -26,6 +34,7 @@ use std::path::{Path,PathBuf};
 use std::thread;
 use std::time;
 use std::process;
+use std::collections::HashMap;
 
 //use std::collections::HashMap;
 use std::error::Error;
@@ -33,18 +42,53 @@ use std::error::Error;
 const QUERY_TOPIC: &str = "MOI/query";
 const QUIT_TOPIC: &str = "MOI/quit";
 const ALIVE_TOPIC: &str = "MOI/alive";
+const EVENT_TOPIC: &str = "MOI/event";
+const PSK_TOPIC: &str = "MOI/psk";
 const GROUP_OP: &str = "__GROUP_OP__";
 
+// how long we remember nonces, if queries may be of any age
+const NONCE_MEMORY: i64 = 3600;
+
+// identity and computed keys which queries can never modify
+const READONLY_KEYS: &[&str] = &["addr","name","time","groups","moid","arch","rc","alive_interval","last_operator","maintenance"];
+
+// set by the 'maintenance' verb, and kept in the store
+const MAINTENANCE_KEY: &str = "maintenance";
+
+// expiry times of keys, as 'expires.KEY'
+const EXPIRES_KEY: &str = "expires";
+
+// set by moid itself on startup, so never part of a snapshot
+const COMPUTED_KEYS: &[&str] = &["addr","name","home","bin","tmp","moid","arch","rc","alive_interval","last_operator"];
+
+// verbs which only look, and so do not change 'last_operator'
+const LOOKING_VERBS: &[&str] = &["get","dump","history","match","validate","wait"];
+
 struct MsgData {
     cfg: SharedPtr<Config>,
     seq: u8,
     m: Mosquitto,
     pending_buffer: Option<Vec<u8>>,
     plugins: SharedPtr<Plugins>,
+    history: SharedPtr<History>,
+    secrets: Secrets,
+    auth: QueryVerifier,
+    policy: Policy,
+    client: String, // who sent the current query, as user@host
+    operator: Option<String>, // who signed it
+    label: Option<String>, // what the sender said it was for
+    dry_run: bool, // only say what the query would do
+    query_window: i64, // how old a query may be, in seconds (0 for any age)
+    untimed_queries: bool, // accept queries without time and nonce, from older moi
+    nonces: HashMap<String,i64>, // recently seen queries and their times
+    sandbox: Option<Vec<PathBuf>>, // the only places queries may touch
+    psk_file: Option<PathBuf>, // our TLS-PSK key, if we use one
+    dry_keys: Vec<String>, // keys an earlier part of a dry-run chain would set
+    toml: toml::Value, // our config, for connecting again
 }
 
 impl MsgData {
-    fn new(cfg: Config, m: &Mosquitto) -> MsgData {
+    fn new(cfg: Config, history: History, secrets: Secrets, auth: QueryVerifier, policy: Policy, m: &Mosquitto) -> MsgData {
         let cfg = make_shared(cfg);
         MsgData {
             cfg: cfg.clone(),
@@ -52,6 +96,21 @@ impl MsgData {
             m: m.clone(),
             pending_buffer: None,
             plugins: make_shared(Plugins::new(cfg)),
+            history: make_shared(history),
+            secrets: secrets,
+            auth: auth,
+            policy: policy,
+            client: "<unknown>".into(),
+            operator: None,
+            label: None,
+            dry_run: false,
+            query_window: 0,
+            untimed_queries: false,
+            nonces: HashMap::new(),
+            sandbox: None,
+            psk_file: None,
+            dry_keys: Vec::new(),
+            toml: toml::Value::Table(toml::value::Table::new()),
         }
     }
 
@@ -74,6 +133,26 @@ impl MsgData {
 
 }
 
+// does the IPv4 address lie within the network ADDR/BITS?
+fn in_cidr(addr: &str, cidr: &str) -> io::Result<bool> {
+    use std::net::Ipv4Addr;
+    let (net,bits) = match cidr.find('/') {
+        Some(idx) => (&cidr[0..idx], &cidr[idx+1..]),
+        None => (cidr, "32")
+    };
+    let net: Ipv4Addr = net.parse()
+        .map_err(|_| io_error(&format!("bad network address {}",cidr)))?;
+    let bits: u32 = bits.parse()
+        .map_err(|_| io_error(&format!("bad network prefix {}",cidr)))?;
+    (bits <= 32).or_then_err(|| format!("network prefix too large {}",cidr))?;
+    let addr: Ipv4Addr = match addr.parse() {
+        Ok(addr) => addr,
+        Err(_) => return Ok(false) // not an address, so no match
+    };
+    let mask = if bits == 0 {0} else {!0u32 << (32 - bits)};
+    Ok(u32::from(addr) & mask == u32::from(net) & mask)
+}
+
 // how a remote knows that a query is intended for itself
 fn match_condition(cfg: &Config, how: &str, condn: &JsonValue) -> io::Result<bool> {
     if how == "any" || how == "all" {
@@ -111,6 +190,21 @@ fn match_condition(cfg: &Config, how: &str, condn: &JsonValue) -> io::Result<boo
             first_val.is_array().or_err("elem only on array values")?;
             first_val.members().any(|v| v == args[1])
         },
+        "cidr" => { // address-valued keys, or arrays of addresses
+            let addrs: Vec<&str> = if first_val.is_array() {
+                first_val.members().filter_map(|v| v.as_str()).collect()
+            } else {
+                first_val.as_str().into_iter().collect()
+            };
+            let mut res = false;
+            for addr in addrs {
+                if in_cidr(addr,args[1])? {
+                    res = true;
+                    break;
+                }
+            }
+            res
+        },
         _ => return Err(io_error(&format!("unknown comparison {}",how)).into())
     })
 }
@@ -144,6 +238,52 @@ fn massage_destination_path(cfg: &Config, dest: String) -> PathBuf {
     }
 }
 
+// the real path, with symlinks and '..' resolved, which must lie within
+// one of the sandbox roots (if we are sandboxed)
+fn sandboxed_path(mdata: &MsgData, path: &Path) -> io::Result<PathBuf> {
+    let real = path.canonicalize()
+        .map_err(|e| io_error(&format!("{}: {}",path.display(),e)))?;
+    if let Some(ref roots) = mdata.sandbox {
+        roots.iter().any(|root| real.starts_with(root))
+            .or_then_err(|| format!("{} is outside the sandbox",path.display()))?;
+    }
+    Ok(real)
+}
+
+// by default queries may only touch the special destinations, as they were on startup.
+// Not 'self', which is just where moid was started (often '/') and holds our keys
+fn sandbox_roots(cfg: &Config, toml_config: &toml::Value) -> BoxResult<Option<Vec<PathBuf>>> {
+    if let Some(false) = toml_config.get("sandbox").and_then(|v| v.as_bool()) {
+        warn!("paths are not sandboxed");
+        return Ok(None);
+    }
+    let roots = if let Some(roots) = toml_config.get("sandbox_roots") {
+        toml_strings(roots.as_array().or_err("'sandbox_roots' must be array")?)?
+    } else {
+        let mut roots = Vec::new();
+        for name in cfg.get("destinations")?.members() {
+            let name = as_str(name)?;
+            if name != "self" {
+                roots.push(cfg.gets(name)?.to_string());
+            }
+        }
+        roots
+    };
+    let mut res = Vec::new();
+    for root in roots {
+        match Path::new(&root).canonicalize() {
+            Ok(path) => {
+                // that would be no sandbox at all
+                (path != Path::new("/"))
+                    .or_then_err(|| format!("sandbox root {} is /: use 'sandbox = false' if you mean it",root))?;
+                res.push(path)
+            },
+            Err(e) => warn!("sandbox root {}: {}",root,e)
+        }
+    }
+    Ok(Some(res))
+}
+
 fn write_result_code(pcfg: &SharedPtr<Config>, code: i32)  {
    let mut cfg = lock!(pcfg);
    cfg.values.insert("rc".into(),code.into());
@@ -167,6 +307,109 @@ fn handle_result_code(pcfg: &SharedPtr<Config>, code: i32) {
     }
 }
 
+// snapshots are kept next to the store as snapshot-NAME.json
+fn snapshot_path(cfg: &Config, args: &JsonValue) -> io::Result<PathBuf> {
+    let name = as_str(args)?;
+    (name.len() > 0 && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_'))
+        .or_then_err(|| format!("bad snapshot name '{}'",name))?;
+    Ok(cfg.file.with_file_name(&format!("snapshot-{}.json",name)))
+}
+
+fn collect_expired(obj: &JsonValue, prefix: &str, now: i64, res: &mut Vec<String>) {
+    for (k,v) in obj.entries() {
+        let key = if prefix.is_empty() {k.to_string()} else {format!("{}.{}",prefix,k)};
+        if v.is_object() {
+            collect_expired(v,&key,now,res);
+        } else
+        if v.as_i64().map(|t| t <= now).unwrap_or(false) {
+            res.push(key);
+        }
+    }
+}
+
+// the broker did not take the new TLS-PSK key, so put the old one back
+fn psk_fallback(m: &Mosquitto, toml: &toml::Value, file: &Path, old: &Path) -> BoxResult<()> {
+    fs::copy(old,file)?;
+    let (identity,key) = read_psk_file(file)?;
+    mosquitto_psk_reconnect(m,toml,&identity,&key)
+}
+
+// a restored value replaces the current one, whatever its type,
+// but the key must be writeable and the schema must allow the value
+fn check_restore(cfg: &mut Config, key: &str, val: &JsonValue) -> io::Result<JsonValue> {
+    let old = cfg.values.remove(key);
+    let res = cfg.check_insert(key,val);
+    if let Some(old) = old {
+        cfg.values.insert(key.into(),old);
+    }
+    res
+}
+
+// computed and read-only keys are not part of a snapshot, and a restore
+// leaves them alone (older snapshots may still have them)
+fn not_snapshotted(cfg: &Config, key: &str) -> bool {
+    COMPUTED_KEYS.contains(&key) || cfg.readonly_keys.iter().any(|k| k == key)
+}
+
+// what restoring a snapshot changes: keys not in the snapshot are removed,
+// but we leave computed and read-only keys alone. Values the schema refuses are skipped
+fn restore_changes(cfg: &mut Config, doc: &JsonValue) -> (Vec<(String,JsonValue)>,JsonValue) {
+    let mut keys: Vec<String> = cfg.values.keys()
+        .filter(|k| doc[k.as_str()].is_null())
+        .cloned().collect();
+    keys.extend(doc.entries().map(|(k,_)| k.to_string()));
+    let mut changes = Vec::new();
+    let mut skipped = JsonValue::new_array();
+    for key in keys {
+        let val = &doc[key.as_str()];
+        if not_snapshotted(cfg,&key) || cfg.values.get(&key).unwrap_or(&JsonValue::Null) == val {
+            continue;
+        }
+        match check_restore(cfg,&key,val) {
+            Ok(val) => changes.push((key,val)),
+            Err(e) => skipped.push(object!{"key" => key.as_str(), "problem" => e.to_string()}).unwrap()
+        }
+    }
+    (changes,skipped)
+}
+
+// the deadline goes even if the key could not be cleared,
+// otherwise we would be trying (and writing the store) every second
+fn forget_expiry(cfg: &mut Config, key: &str) {
+    let fields: Vec<&str> = key.split('.').collect();
+    let (last,parents) = fields.split_last().unwrap();
+    if let Some(mut obj) = cfg.values.get_mut(EXPIRES_KEY) {
+        for field in parents {
+            obj = &mut obj[*field];
+        }
+        obj.remove(last);
+    }
+}
+
+// housekeeping: remove keys which are past their expiry time
+fn expire_keys(cfg: &mut Config, history: &mut History) {
+    let mut expired = Vec::new();
+    if let Some(expires) = cfg.values.get(EXPIRES_KEY) {
+        collect_expired(expires,"",current_time_as_secs(),&mut expired);
+    }
+    if expired.is_empty() {
+        return;
+    }
+    for key in expired {
+        let old = cfg.get_or(&key,JsonValue::Null);
+        if let Err(e) = cfg.insert(&key,&JsonValue::Null) {
+            error!("expiring {}: {}",key,e);
+        } else {
+            info!("key {} expired",key);
+            history.record(&key,&old,&JsonValue::Null,"<expired>");
+        }
+        forget_expiry(cfg,&key);
+    }
+    if let Err(e) = cfg.write() {
+        error!("expiring keys: {}",e);
+    }
+}
+
 // get a list of keys
 fn populate_result_array(cfg: &Config, plugins: &Plugins, args: &JsonValue) -> BoxResult<JsonValue> {
     let mut res = JsonValue::new_array();
@@ -174,15 +417,53 @@ fn populate_result_array(cfg: &Config, plugins: &Plugins, args: &JsonValue) -> B
         let s = as_str(s)?; // keys must be strings...
         if let Some(val) = plugins.var(s) { // they may be Special
             res.push(val)?;
+        } else
+        if secrets::within_secret(cfg,s) { // never give these away
+            res.push(secrets::REDACTED)?;
         } else {
             // but we return Null if not-found
-            res.push(cfg.get_or(s,JsonValue::Null).clone())?;
+            res.push(secrets::redact(&cfg.get_or(s,JsonValue::Null)))?;
         }
     }
     Ok(res)
 }
 
+// counters: missing keys start at zero
+fn counter_value(cfg: &Config, verb: &str, key: &str, by: &JsonValue) -> io::Result<JsonValue> {
+    let by = by.as_f64().or_then_err(|| format!("{}: amount for {} must be a number",verb,key))?;
+    let old = cfg.get_or(key,JsonValue::Null);
+    let current = if old.is_null() {
+        0.0
+    } else {
+        old.as_f64().or_then_err(|| format!("{}: key '{}' is not a number",verb,key))?
+    };
+    Ok(JsonValue::from(if verb == "inc" {current + by} else {current - by}))
+}
+
+// compare-and-set: the key and its new value, only if it has the expected value
+fn cas_value<'a>(cfg: &Config, args: &'a JsonValue) -> io::Result<(&'a str,&'a JsonValue)> {
+    let key = string_field(args,"key")?;
+    let (expect,val) = (&args["expect"], &args["value"]);
+    // like set, secrets may only be removed
+    (val.is_null() || ! secrets::within_secret(cfg,key))
+        .or_then_err(|| format!("key '{}' is secret",key))?;
+    let old = cfg.get_or(key,JsonValue::Null);
+    // moi sends plain strings, so compare as it would display
+    let same = old == *expect || (expect.is_string() && old.to_string() == expect.to_string());
+    same.or_then_err(|| format!("cas: key '{}' is {}, not {}",key,secrets::redact(&old),expect))?;
+    Ok((key,val))
+}
+
 fn handle_verb(mdata: &mut MsgData, verb: &str, args: &JsonValue) -> BoxResult<JsonValue> {
+    // a chain is only as allowed as its parts
+    if verb != "chain" {
+        mdata.policy.check_verb(mdata.operator.as_ref(),verb)?;
+    }
+    if mdata.dry_run {
+        if let Some(res) = dry_run(mdata,verb,args)? {
+            return Ok(res);
+        }
+    }
     if verb == "get" {
         let cfg = lock!(mdata.cfg);
         let plugins = lock!(mdata.plugins);
@@ -190,47 +471,130 @@ fn handle_verb(mdata: &mut MsgData, verb: &str, args: &JsonValue) -> BoxResult<J
     } else
     if verb == "set" {
         let mut cfg = lock!(mdata.cfg);
+        let mut history = lock!(mdata.history);
         // set keys on this device
         for (key,val) in args.entries() {
+            // secrets may only be removed
+            (val.is_null() || ! secrets::within_secret(&cfg,key))
+                .or_then_err(|| format!("key '{}' is secret",key))?;
+            let old = cfg.get_or(key,JsonValue::Null);
             cfg.insert(key, val)?;
+            // a new value does not inherit the old expiry time
+            cfg.insert(&format!("{}.{}",EXPIRES_KEY,key),&JsonValue::Null)?;
+            history.record(key,&old,&cfg.get_or(key,JsonValue::Null),&mdata.client);
         }
         // we persist the values immediately...
         cfg.write()?;
         Ok(JsonValue::from(true))
     } else
+    if verb == "secret" {
+        // values are encrypted before they go anywhere near the store
+        let mut cfg = lock!(mdata.cfg);
+        let mut history = lock!(mdata.history);
+        for (key,val) in args.entries() {
+            let plain = val.as_str().or_then_err(|| format!("secret {} must be a string",key))?;
+            let old = cfg.get_or(key,JsonValue::Null);
+            if ! secrets::is_secret(&old) { // an ordinary value becomes a secret
+                cfg.insert(key,&JsonValue::Null)?;
+            }
+            let val = mdata.secrets.encrypt(plain)?;
+            cfg.insert(key,&val)?;
+            cfg.insert(&format!("{}.{}",EXPIRES_KEY,key),&JsonValue::Null)?;
+            history.record(key,&old,&val,&mdata.client);
+        }
+        cfg.write()?;
+        Ok(JsonValue::from(true))
+    } else
+    if verb == "inc" || verb == "dec" {
+        let mut cfg = lock!(mdata.cfg);
+        let mut history = lock!(mdata.history);
+        let mut res = JsonValue::new_object();
+        for (key,by) in args.entries() {
+            let old = cfg.get_or(key,JsonValue::Null);
+            let val = counter_value(&cfg,verb,key,by)?;
+            cfg.insert(key,&val)?;
+            history.record(key,&old,&val,&mdata.client);
+            res[key] = val;
+        }
+        cfg.write()?;
+        Ok(res)
+    } else
+    if verb == "cas" {
+        let mut cfg = lock!(mdata.cfg);
+        let (key,val) = cas_value(&cfg,args)?;
+        let old = cfg.get_or(key,JsonValue::Null);
+        cfg.insert(key,val)?;
+        lock!(mdata.history).record(key,&old,val,&mdata.client);
+        cfg.write()?;
+        Ok(JsonValue::from(true))
+    } else
+    if verb == "expire" {
+        // keys will be removed after the given number of seconds
+        let mut cfg = lock!(mdata.cfg);
+        let mut history = lock!(mdata.history);
+        let now = current_time_as_secs();
+        for (key,secs) in args.entries() {
+            let secs = secs.as_i64().or_then_err(|| format!("expiry of {} must be in seconds",key))?;
+            cfg.get(key)?;
+            // we must be able to clear the key when the time comes
+            cfg.check_insert(key,&JsonValue::Null)?;
+            let expiry = format!("{}.{}",EXPIRES_KEY,key);
+            let old = cfg.get_or(&expiry,JsonValue::Null);
+            let deadline = JsonValue::from(now + secs);
+            cfg.insert(&expiry,&deadline)?;
+            history.record(&expiry,&old,&deadline,&mdata.client);
+        }
+        cfg.write()?;
+        Ok(JsonValue::from(true))
+    } else
     if verb == "seta" || verb == "rma" {
         let mut cfg = lock!(mdata.cfg);
+        let mut history = lock!(mdata.history);
         // these both modify array-valued keys - rma removes
         // a value from the array if present
         for (key,val) in args.entries() {
+            let old = cfg.get_or(key,JsonValue::Null);
             cfg.insert_array(key, val, verb == "rma")?;
+            history.record(key,&old,&cfg.get_or(key,JsonValue::Null),&mdata.client);
         }
         cfg.write()?;
         Ok(JsonValue::from(true))
     } else
     if verb == "run" || verb == "launch" || verb == "spawn" {
         // global tilde substitution needed for standalone tests PASOP
-        let (cmd,pwd) = {
+        let (cmd,pwd,env) = {
             let cfg = lock!(mdata.cfg);
             let home = cfg.home();
-            let cmd = string_field(args,"cmd")?.replace('~',home);
+            let cmd = string_field(args,"cmd")?;
+            mdata.policy.check_command(mdata.operator.as_ref(),cmd)?;
+            let cmd = cmd.replace('~',home);
             let pwd = string_field(args,"pwd").unwrap_or(home);
             let pwd = massage_destination_path(&cfg,pwd.into());
-            (cmd,pwd)
+            let mut env = mdata.secrets.environment(&cfg);
+            // so jobs know who asked for them
+            env.push(("MOI_OPERATOR".into(),mdata.client.clone()));
+            if let Some(ref label) = mdata.label {
+                env.push(("MOI_OPERATOR_LABEL".into(),label.clone()));
+            }
+            (cmd,pwd,env)
         };
         // check explicitly here because otherwise run_shell_command panics..
         // TODO case where parent exists - don't join filename to dest
         (pwd.exists() && pwd.is_dir())
             .or_then_err(|| format!("run: dest does not exist {}",pwd.display()))?;
+        let pwd = sandboxed_path(mdata,&pwd)?;
+        if mdata.dry_run {
+            return Ok(JsonValue::from(format!("would {} '{}' in {}",verb,cmd,pwd.display())));
+        }
         if verb == "run" {
             // we Wait....
-            let (code, stdout, stderr) = run_shell_command(&cmd,Some(&pwd));
+            let (code, stdout, stderr) = run_shell_command(&cmd,Some(&pwd),&env);
             handle_result_code(&mdata.cfg,code);
             Ok(object!{"code" => code, "stdout" => stdout, "stderr" => stderr})
         } else
         if verb == "spawn" {
             // we Let Go
-            spawn_shell_command(&cmd,Some(&pwd));
+            spawn_shell_command(&cmd,Some(&pwd),&env);
             Ok(JsonValue::from(true))
         } else {
             // We immediately return with ok but send results
@@ -243,9 +607,11 @@ fn handle_verb(mdata: &mut MsgData, verb: &str, args: &JsonValue) -> BoxResult<J
             let seq = mdata.seq + 1;
             let addr = lock!(mdata.cfg).addr().to_string();
             let shared_cfg = mdata.cfg.clone();
+            let shared_history = mdata.history.clone();
+            let client = mdata.client.clone();
             let jobname = string_field(args,"job").unwrap_or("<none>").to_string();
             thread::spawn(move || {
-                let (code, stdout, stderr) = run_shell_command(&cmd,Some(&pwd));
+                let (code, stdout, stderr) = run_shell_command(&cmd,Some(&pwd),&env);
                 let res = object!{"code" => code, "stdout" => stdout, "stderr" => stderr};
                 if jobname == "<none>" {
                     // MOI is waiting for us most patiently...
@@ -254,8 +620,13 @@ fn handle_verb(mdata: &mut MsgData, verb: &str, args: &JsonValue) -> BoxResult<J
                 } else {
                     // not waiting, so we put the result into the store using jobname
                     let mut cfg = lock!(shared_cfg);
-                    cfg.insert(&jobname,&res).unwrap();
-                    cfg.write().unwrap();
+                    let old = cfg.get_or(&jobname,JsonValue::Null);
+                    if let Err(e) = cfg.insert(&jobname,&res) {
+                        error!("job {} result: {}",jobname,e);
+                    } else {
+                        lock!(shared_history).record(&jobname,&old,&res,&client);
+                        cfg.write().unwrap();
+                    }
                 }
                 // either way, flag 'rc' if we failed!
                 handle_result_code(&shared_cfg,code);
@@ -272,6 +643,15 @@ fn handle_verb(mdata: &mut MsgData, verb: &str, args: &JsonValue) -> BoxResult<J
         let filename = string_field(args,"filename")?;
         let dest = string_field(args,"dest")?.to_string();
         let dest: PathBuf =  massage_destination_path(&lock!(mdata.cfg),dest);
+        writeable_directory(&dest)?;
+        let dest = sandboxed_path(mdata,&dest)?;
+        mdata.policy.check_destination(mdata.operator.as_ref(),&dest)?;
+        // the file goes into dest, and nowhere else
+        (Path::new(filename).file_name() == Some(std::ffi::OsStr::new(filename)))
+            .or_then_err(|| format!("bad file name {}",filename))?;
+        if dest.join(filename).exists() { // might be a symlink to elsewhere
+            sandboxed_path(mdata,&dest.join(filename))?;
+        }
 
         let maybe_perms = &args["perms"];
         let maybe_hash = &args["hash"];
@@ -288,12 +668,19 @@ fn handle_verb(mdata: &mut MsgData, verb: &str, args: &JsonValue) -> BoxResult<J
         } else {
             None
         };
-        writeable_directory(&dest)?;
+        let sha256 = args["sha256"].as_str().map(|s| s.to_string());
+        // signing covers the query, not the file contents which follow.
+        // MD5 is no good for that, so signed queries must give SHA-256
+        (sha256.is_some() || ! mdata.auth.required()).or_err("cp: signed queries must give the file's SHA-256")?;
+        if mdata.dry_run {
+            return Ok(JsonValue::from(format!("would copy {} to {}",filename,dest.display())));
+        }
         lock!(mdata.cfg).pending_file = Some(FilePending {
             filename: filename.into(),
             dest: dest.join(filename),
             perms: perms,
             hash: hash,
+            sha256: sha256,
         });
         //println!("pending file set {:?}",cfg.pending_file);
         Ok(JsonValue::from(true))
@@ -301,6 +688,11 @@ fn handle_verb(mdata: &mut MsgData, verb: &str, args: &JsonValue) -> BoxResult<J
     if verb == "fetch" {
         let source = massage_destination_path(&lock!(mdata.cfg),string_field(args,"source")?.into());
         source.exists().or_then_err(|| format!("remote source {} does not exist",source.display()))?;
+        let source = sandboxed_path(mdata,&source)?;
+        mdata.policy.check_destination(mdata.operator.as_ref(),&source)?;
+        if mdata.dry_run {
+            return Ok(JsonValue::from(format!("would send {}",source.display())));
+        }
         mdata.pending_buffer = Some(read_to_buffer(&source)?);
         Ok(JsonValue::from(true))
     } else
@@ -313,6 +705,76 @@ fn handle_verb(mdata: &mut MsgData, verb: &str, args: &JsonValue) -> BoxResult<J
         });
         Ok(JsonValue::from(true))
     } else
+    if verb == "maintenance" {
+        // only queries marked for maintenance get through, until this is switched off
+        let on = args.as_bool().or_err("maintenance: expects true or false")?;
+        let mut cfg = lock!(mdata.cfg);
+        let old = cfg.get_or(MAINTENANCE_KEY,JsonValue::Null);
+        if on {
+            cfg.insert_into(MAINTENANCE_KEY,true);
+        } else {
+            cfg.values.remove(MAINTENANCE_KEY);
+        }
+        let new = cfg.get_or(MAINTENANCE_KEY,JsonValue::Null);
+        lock!(mdata.history).record(MAINTENANCE_KEY,&old,&new,&mdata.client);
+        if let Some(ref hook) = cfg.on_change {
+            if old != new {
+                hook(MAINTENANCE_KEY,&old,&new);
+            }
+        }
+        cfg.write()?;
+        warn!("maintenance mode {}",if on {"on"} else {"off"});
+        Ok(JsonValue::from(true))
+    } else
+    if verb == "psk" {
+        // a new TLS-PSK identity and key: we switch over once we have replied,
+        // and tell moi on MOI/psk how it went
+        let file = mdata.psk_file.clone().or_err("psk: not using TLS-PSK")?;
+        let identity = string_field(args,"identity")?.to_string();
+        let key = string_field(args,"key")?.to_string();
+        (! identity.is_empty() && ! identity.contains(|c| c == ':' || c == '\n'))
+            .or_err("psk: bad identity")?;
+        from_hex(&key).map_err(|_| io_error("psk: key must be hex"))?;
+        let addr = lock!(mdata.cfg).addr().to_string();
+        {
+            // a key the broker does not know should cost us nothing
+            let config = mdata.toml.get("config").or_err("no [config] section")?;
+            let test = mosquitto_psk_setup(&format!("MOID-{}-psk",addr),config,&mdata.toml,&identity,&key)
+                .map_err(|e| io_error(&format!("psk: broker does not accept identity {}: {}",identity,e)))?;
+            let _ = test.disconnect();
+        }
+        // keep the old key around, in case we need to go back to it
+        let old = PathBuf::from(format!("{}.old",file.display()));
+        fs::copy(&file,&old)?;
+        let tmp = PathBuf::from(format!("{}.tmp",file.display()));
+        {
+            let mut f = fs::OpenOptions::new().create(true).truncate(true).write(true).mode(0o600)
+                .open(&tmp)?;
+            write!(f,"{}:{}\n",identity,key)?;
+        }
+        fs::rename(&tmp,&file)?;
+        warn!("new TLS-PSK identity {}: reconnecting",identity);
+        let m = mdata.m.clone();
+        let toml = mdata.toml.clone();
+        thread::spawn(move || {
+            thread::sleep(time::Duration::from_millis(100));
+            let report = match mosquitto_psk_reconnect(&m,&toml,&identity,&key) {
+                Ok(_) => object!{"addr" => addr.as_str(), "identity" => identity.as_str(), "ok" => true},
+                Err(e) => {
+                    error!("psk: cannot connect as {} ({}): going back to the old key",identity,e);
+                    if let Err(e) = psk_fallback(&m,&toml,&file,&old) {
+                        error!("psk: cannot connect with the old key either ({}): restarting",e);
+                        process::exit(1);
+                    }
+                    object!{"addr" => addr.as_str(), "identity" => identity.as_str(), "ok" => false, "error" => e.to_string()}
+                }
+            };
+            if let Err(e) = m.publish(PSK_TOPIC,report.to_string().as_bytes(),1,false) {
+                error!("psk: report failed {}",e);
+            }
+        });
+        Ok(JsonValue::from(true))
+    } else
     if verb == "chain" {
         let mut res = JsonValue::new_array();
         for q in args.members() {
@@ -321,6 +783,78 @@ fn handle_verb(mdata: &mut MsgData, verb: &str, args: &JsonValue) -> BoxResult<J
         }
         Ok(res)
     } else
+    if verb == "snapshot" {
+        let cfg = lock!(mdata.cfg);
+        let path = snapshot_path(&cfg,args)?;
+        let mut doc = JsonValue::new_object();
+        for (k,v) in cfg.values.iter() {
+            if ! not_snapshotted(&cfg,k) {
+                doc[k] = v.clone();
+            }
+        }
+        write_all(&path,&json::stringify_pretty(doc,2))?;
+        info!("snapshot {}",path.display());
+        Ok(JsonValue::from(true))
+    } else
+    if verb == "restore" {
+        let mut cfg = lock!(mdata.cfg);
+        let mut history = lock!(mdata.history);
+        let path = snapshot_path(&cfg,args)?;
+        path.exists().or_then_err(|| format!("no snapshot {}",path.display()))?;
+        let doc = json::parse(&read_to_string(&path)?)?;
+        let (changes,skipped) = restore_changes(&mut cfg,&doc);
+        for (key,val) in changes {
+            let old = if val.is_null() {
+                cfg.values.remove(&key)
+            } else {
+                cfg.values.insert(key.clone(),val.clone())
+            }.unwrap_or(JsonValue::Null);
+            history.record(&key,&old,&val,&mdata.client);
+            if let Some(ref hook) = cfg.on_change {
+                hook(&key,&old,&val);
+            }
+        }
+        cfg.write()?;
+        for s in skipped.members() {
+            warn!("restore: skipped {}: {}",s["key"],s["problem"]);
+        }
+        info!("restored {}",path.display());
+        Ok(skipped)
+    } else
+    if verb == "dump" {
+        // the whole store, or just the keys starting with a prefix
+        let cfg = lock!(mdata.cfg);
+        let prefix = args.as_str().unwrap_or("");
+        let mut res = JsonValue::new_object();
+        for (k,v) in cfg.values.iter() {
+            if k.starts_with(prefix) {
+                res[k.as_str()] = secrets::redact(v);
+            }
+        }
+        Ok(res)
+    } else
+    if verb == "validate" {
+        // existing values which the schema does not like
+        Ok(lock!(mdata.cfg).validate())
+    } else
+    if verb == "history" {
+        // args is a key, or null for all changes
+        Ok(lock!(mdata.history).changes(args.as_str()))
+    } else
+    if verb == "match" {
+        // evaluate several conditions at once (used by 'moi filters')
+        let cfg = lock!(mdata.cfg);
+        let mut res = JsonValue::new_array();
+        for condn in args.members() {
+            let matched = if let Some((how,condn)) = condn.entries().next() {
+                match_condition(&cfg,how,condn)?
+            } else { // the empty condition matches everyone
+                true
+            };
+            res.push(matched)?;
+        }
+        Ok(res)
+    } else
     if verb == "wait" {
         Ok(JsonValue::Null)
     } else {
@@ -332,56 +866,237 @@ fn handle_verb(mdata: &mut MsgData, verb: &str, args: &JsonValue) -> BoxResult<J
     }
 }
 
+// what a query would do to the store, or to moid itself, checked but not done.
+// Commands and files are checked in handle_verb, which stops just before acting.
+fn dry_run(mdata: &mut MsgData, verb: &str, args: &JsonValue) -> BoxResult<Option<JsonValue>> {
+    let mut cfg = lock!(mdata.cfg);
+    let mut changes = Vec::new();
+    if verb == "set" {
+        for (key,val) in args.entries() {
+            (val.is_null() || ! secrets::within_secret(&cfg,key))
+                .or_then_err(|| format!("key '{}' is secret",key))?;
+            let val = cfg.check_insert(key,val)?;
+            changes.push(if val.is_null() {format!("clear {}",key)} else {format!("set {}={}",key,val)});
+            if ! val.is_null() {
+                mdata.dry_keys.push(key.to_string());
+            }
+        }
+    } else
+    if verb == "secret" {
+        for (key,val) in args.entries() {
+            val.as_str().or_then_err(|| format!("secret {} must be a string",key))?;
+            cfg.check_insert(key,&JsonValue::Null)?;
+            changes.push(format!("set {}={}",key,secrets::REDACTED));
+        }
+    } else
+    if verb == "inc" || verb == "dec" {
+        for (key,by) in args.entries() {
+            let val = cfg.check_insert(key,&counter_value(&cfg,verb,key,by)?)?;
+            changes.push(format!("set {}={}",key,val));
+        }
+    } else
+    if verb == "cas" {
+        let (key,val) = cas_value(&cfg,args)?;
+        changes.push(format!("set {}={}",key,cfg.check_insert(key,val)?));
+    } else
+    if verb == "expire" {
+        for (key,secs) in args.entries() {
+            let secs = secs.as_i64().or_then_err(|| format!("expiry of {} must be in seconds",key))?;
+            // (the key may be set earlier in the chain, like 'set --ttl' does)
+            if ! mdata.dry_keys.iter().any(|k| k == key) {
+                cfg.get(key)?;
+            }
+            cfg.check_insert(key,&JsonValue::Null)?;
+            changes.push(format!("expire {} in {}s",key,secs));
+        }
+    } else
+    if verb == "seta" || verb == "rma" {
+        for (key,val) in args.entries() {
+            cfg.check_insert_array(key,val,verb == "rma")?;
+            changes.push(if verb == "seta" {format!("add {} to {}",val,key)} else {format!("remove {} from {}",val,key)});
+        }
+    } else
+    if verb == "restart" {
+        args.as_i32().or_err("process code must be integer")?;
+        changes.push("restart".into());
+    } else
+    if verb == "maintenance" {
+        let on = args.as_bool().or_err("maintenance: expects true or false")?;
+        changes.push(format!("{} maintenance mode",if on {"enter"} else {"leave"}));
+    } else
+    if verb == "psk" {
+        mdata.psk_file.as_ref().or_err("psk: not using TLS-PSK")?;
+        changes.push(format!("switch to TLS-PSK identity {} and reconnect",string_field(args,"identity")?));
+    } else
+    if verb == "snapshot" {
+        changes.push(format!("save snapshot {}",snapshot_path(&cfg,args)?.display()));
+    } else
+    if verb == "restore" {
+        let path = snapshot_path(&cfg,args)?;
+        path.exists().or_then_err(|| format!("no snapshot {}",path.display()))?;
+        let doc = json::parse(&read_to_string(&path)?)?;
+        let (keys,skipped) = restore_changes(&mut cfg,&doc);
+        changes.push(format!("restore snapshot {} ({} keys changed)",path.display(),keys.len()));
+        for s in skipped.members() {
+            changes.push(format!("skip {}: {}",s["key"],s["problem"]));
+        }
+    } else
+    if LOOKING_VERBS.contains(&verb) || verb == "chain" || verb == "cp" || verb == "fetch"
+        || verb == "run" || verb == "launch" || verb == "spawn" {
+        return Ok(None);
+    } else {
+        // plugins can't tell us what they would do
+        changes.push(format!("invoke {} (not checked)",verb));
+    }
+    Ok(Some(JsonValue::from(format!("would {}",changes.join(", ")))))
+}
+
+// queries must be recent, and are only accepted once
+fn check_fresh(mdata: &mut MsgData, query: &JsonValue) -> io::Result<()> {
+    let (time,nonce) = match (query["time"].as_i64(),query["nonce"].as_str()) {
+        (Some(time),Some(nonce)) => (time,nonce),
+        _ if mdata.untimed_queries => return Ok(()),
+        _ => return Err(io_error("query has no time or nonce (from an older moi? see 'untimed_queries')"))
+    };
+    let now = current_time_as_secs();
+    let window = mdata.query_window;
+    if window != 0 {
+        ((now - time).abs() <= window)
+            .or_then_err(|| format!("stale query: sent {}s ago (check the clocks?)",now - time))?;
+    }
+    // nonces older than the window can be forgotten, since their queries are now stale.
+    // Without a window, we remember when we saw them instead
+    let (keep,seen) = if window != 0 {(window,time)} else {(NONCE_MEMORY,now)};
+    mdata.nonces.retain(|_,t| (now - *t).abs() <= keep);
+    (! mdata.nonces.contains_key(nonce)).or_err("repeated query")?;
+    mdata.nonces.insert(nonce.to_string(),seen);
+    Ok(())
+}
+
+// with keys, quit must be signed like a query, otherwise anyone
+// could shut down the whole fleet
+fn check_quit(mdata: &mut MsgData, txt: &str) -> BoxResult<()> {
+    if ! mdata.auth.required() {
+        return Ok(());
+    }
+    let envelope = json::parse(txt)?;
+    let query = json::parse(mdata.auth.verify(&envelope)?.0)?;
+    check_fresh(mdata,&query)?;
+    Ok(())
+}
+
+// we stay quiet, except for _group operations_ where we must make some response
+fn not_for_us(mdata: &MsgData, query: &JsonValue) -> BoxResult<JsonValue> {
+    let group_op = if let Some(is_group) = maybe_field(query,"group") {
+        let group = is_group.as_str().or_err("group field must be string")?;
+        let cfg = lock!(mdata.cfg);
+        if let Some(groups) = cfg.values.get("groups") {
+            groups.members().any(|v| v == group)
+        } else { // no groups available
+            false
+        }
+    } else {
+        false
+    };
+    Ok(if group_op {JsonValue::String(GROUP_OP.into())} else {JsonValue::Null})
+}
+
+fn in_maintenance(cfg: &Config) -> bool {
+    cfg.get_or(MAINTENANCE_KEY,JsonValue::Null).as_bool() == Some(true)
+}
+
 fn handle_query(mdata: &mut MsgData, txt: &str) -> BoxResult<JsonValue> {
-    let query = json::parse(txt)?;
+    let envelope = json::parse(txt)?;
+    let query = match envelope["signed"].as_str() {
+        Some(text) => json::parse(text)?,
+        None => envelope.clone()
+    };
     mdata.seq = query["seq"].as_u8().or_err("bad seq")?;
+    // (we need seq to tell moi why we refuse the query)
+    let operator = if mdata.auth.required() {
+        mdata.auth.verify(&envelope)?.1
+    } else {
+        None
+    };
+    // an operator who signed the query is more believable than 'from'
+    let who = operator.clone().unwrap_or_else(|| query["from"].as_str().unwrap_or("<unknown>").to_string());
+    mdata.client = match query["host"].as_str() {
+        Some(host) => format!("{}@{}",who,host),
+        None => who
+    };
+    mdata.operator = operator;
+    mdata.label = query["label"].as_str().map(|s| s.to_string());
+    mdata.dry_run = query["dry_run"].as_bool() == Some(true);
+    mdata.dry_keys.clear();
+    check_fresh(mdata,&query)?;
     if let Some((how,condn)) = query["which"].entries().next() {
         // is this query intended for us?
         let yes = match_condition(&lock!(mdata.cfg),how,condn)?;
         if ! yes { // not for us!
-            // NB for _group operations_ that we make some response
-            let group_op = if let Some(is_group) = maybe_field(&query,"group") {
-                let group = is_group.as_str().or_err("group field must be string")?;
-                let cfg = lock!(mdata.cfg);
-                if let Some(groups) = cfg.values.get("groups") {
-                    groups.members().any(|v| v == group)
-                } else { // no groups available
-                    false
-                }
-            } else {
-                false
-            };
-            return Ok(if group_op {JsonValue::String(GROUP_OP.into())} else {JsonValue::Null});
+            return not_for_us(mdata,&query);
         }
         info!("condition {} {}",how,condn);
     }
     let (verb,args) = query["what"].entries().next()
         .or_err("query must have 'what'")?;
-    info!("query {} {}",verb,args);
-    handle_verb(mdata,verb,args)
+    // in maintenance mode, we only answer queries which say they are fine with that
+    if in_maintenance(&lock!(mdata.cfg)) && query["maintenance"].as_bool() != Some(true) {
+        info!("maintenance: ignoring query {} by {}",verb,mdata.client);
+        return not_for_us(mdata,&query);
+    }
+    let mut by = match mdata.label {
+        Some(ref label) => format!("{} ({})",mdata.client,label),
+        None => mdata.client.clone()
+    };
+    if mdata.dry_run {
+        by += " [dry run]";
+    }
+    if verb == "secret" || verb == "psk" { // don't log the values!
+        info!("query {} {} by {}",verb,args.entries().map(|(k,_)| k).collect::<Vec<_>>().join(","),by);
+    } else {
+        info!("query {} {} by {}",verb,args,by);
+    }
+    let res = handle_verb(mdata,verb,args)?;
+    // only queries which got through get to be the last operator
+    if ! LOOKING_VERBS.contains(&verb) && ! mdata.dry_run {
+        lock!(mdata.cfg).insert_into("last_operator",by);
+    }
+    Ok(res)
 }
 
 fn handle_file(mdata: &mut MsgData, msg: &MosqMessage) -> io::Result<bool> {
-    let res = if let Some(ref file) = lock!(mdata.cfg).pending_file {
-        let payload = msg.payload();
-        let mut oo = fs::OpenOptions::new();
-        oo.create(true).write(true);
-        if let Some(perms) = file.perms {
-            oo.mode(perms);
-        }
-        let mut outf = oo.open(&file.dest)?;
-        outf.write_all(payload)?;
-        if let Some(ref hash) = file.hash {
-            let digest = md5::compute(&payload);
-            let sd = format!("{:x}",digest);
-            (sd == hash.as_str()).or_then_err(|| format!("received hash was {} not {}",sd,hash))?;
-        }
-        true
-    } else {
-        false
+    // done with the pending file, whatever happens
+    let file = match lock!(mdata.cfg).pending_file.take() {
+        Some(file) => file,
+        None => return Ok(false)
     };
-    lock!(mdata.cfg).pending_file = None; // done!
-    Ok(res)
+    let payload = msg.payload();
+    // the file topic is not signed, but the hash in the cp query may be
+    if let Some(ref hash) = file.sha256 {
+        let sd = auth::file_hash(&payload);
+        (sd == hash.as_str()).or_then_err(|| format!("received SHA-256 was {} not {}",sd,hash))?;
+    } else
+    if let Some(ref hash) = file.hash {
+        let digest = md5::compute(&payload);
+        let sd = format!("{:x}",digest);
+        (sd == hash.as_str()).or_then_err(|| format!("received hash was {} not {}",sd,hash))?;
+    }
+    // written alongside the destination, and only moved there when complete
+    let tmp = file.dest.with_file_name(format!(".{}.moi-tmp",file.filename));
+    let _ = fs::remove_file(&tmp);
+    let mut oo = fs::OpenOptions::new();
+    oo.create_new(true).write(true);
+    if let Some(perms) = file.perms {
+        oo.mode(perms);
+    }
+    let res = oo.open(&tmp)
+        .and_then(|mut outf| outf.write_all(payload).and_then(|_| outf.sync_all()))
+        .and_then(|_| fs::rename(&tmp,&file.dest));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res?;
+    Ok(true)
 }
 
 fn logging_init(cfg: &toml::Value, def: &str) -> BoxResult<()> {
@@ -436,8 +1151,21 @@ fn run() -> BoxResult<()> {
         gets_or_then(toml_config,"home",|| env::var("HOME").expect("no damn HOME"))?
     };
 
-    let json_store = gets_or_then(toml_config,"store",|| format!("{}/store.json",var_moid))?;
-    let mut store = Config::new_from_file(&toml_config, &PathBuf::from(json_store))?;
+    // before the store, which may need to complain about backups
+    logging_init(&toml_config, &var_moid)?;
+
+    let json_store = PathBuf::from(gets_or_then(toml_config,"store",|| format!("{}/store.json",var_moid))?);
+    let mut store = Config::new_from_file(&toml_config, &json_store)?;
+
+    // history of changes is kept next to the store
+    let history_file = json_store.with_file_name("history.json");
+    let history = History::new(history_file,geti_or(toml_config,"history_size",100)? as usize)?;
+
+    // key for encrypting secret values
+    let secret_key = gets_or_then(toml_config,"secret_key",|| {
+        json_store.with_file_name("secret.key").to_str().unwrap().to_string()
+    })?;
+    let secrets = Secrets::new(Path::new(&secret_key))?;
 
     store.insert_into("moid",VERSION);
     store.insert_into("arch",env::consts::ARCH);
@@ -467,11 +1195,35 @@ fn run() -> BoxResult<()> {
         store.insert_into("destinations", arr );
     }
 
-    logging_init(&toml_config, &var_moid)?;
+    // further read-only keys and key prefixes may be configured
+    store.readonly_keys = READONLY_KEYS.iter().map(|s| s.to_string()).collect();
+    if let Some(keys) = toml_config.get("readonly_keys") {
+        let keys = keys.as_array().or_err("'readonly_keys' must be array")?;
+        store.readonly_keys.extend(toml_strings(&keys)?);
+    }
+    if let Some(prefixes) = toml_config.get("readonly_prefixes") {
+        let prefixes = prefixes.as_array().or_err("'readonly_prefixes' must be array")?;
+        store.readonly_prefixes = toml_strings(&prefixes)?;
+    }
+
+    // types, defaults and allowed values of keys
+    if let Some(schema) = toml.get("schema") {
+        toml_schema(schema,"",&mut store.schema)?;
+        for problem in store.validate().members() {
+            warn!("schema: key '{}' {}",problem["key"],problem["problem"]);
+        }
+    }
+
 
     // VERY important that mosquitto client name is unique, otherwise Mosquitto has kittens
     let mosq_name = format!("MOID-{}",&store.addr());
     let default_cert_dir = PathBuf::from(store.gets("self")?).join("certs");
+    // (TLS certificates win over TLS-PSK)
+    let psk_file = if toml.get("tls").is_none() {
+        tls_psk_file(&toml,&default_cert_dir)?
+    } else {
+        None
+    };
     let m = mosquitto_setup(&mosq_name,&toml_config,&toml,default_cert_dir)?;
 
     let query = m.subscribe(QUERY_TOPIC,1)?;
@@ -492,9 +1244,48 @@ fn run() -> BoxResult<()> {
     };
     let alive_vars = JsonValue::from(alive_vars);
 
-    let mut mc = m.callbacks(MsgData::new(store,&m));
+    // changes to the store are published as MOI/event/ADDR/KEY
+    let event_m = m.clone();
+    let event_addr = store.addr().to_string();
+    let event_name = store.gets("name")?.to_string();
+    store.on_change = Some(Box::new(move |key,old,new| {
+        let topic = format!("{}/{}/{}",EVENT_TOPIC,event_addr,key);
+        let event = object!{
+            "addr" => event_addr.as_str(),
+            "name" => event_name.as_str(),
+            "key" => key,
+            "old" => secrets::redact(old),
+            "new" => secrets::redact(new),
+            "time" => current_time_as_secs()
+        };
+        if let Err(e) = event_m.publish(&topic,event.to_string().as_bytes(),1,false) {
+            error!("event {}: {}",topic,e);
+        }
+    }));
+
+    // queries must be signed if there is an [auth] section
+    let config_dir = Path::new(&file).parent().unwrap_or(Path::new(".")).to_path_buf();
+    let auth = QueryVerifier::new(toml.get("auth"),&config_dir)?;
+    if auth.required() {
+        info!("only accepting signed queries");
+    }
+    let policy = Policy::new(toml.get("policy"))?;
+    let query_window = geti_or(toml_config,"query_window",60)?;
+    let untimed_queries = toml_config.get("untimed_queries").and_then(|v| v.as_bool()) == Some(true);
+    if untimed_queries {
+        warn!("accepting queries without time and nonce: these may be replayed");
+    }
+    let sandbox = sandbox_roots(&store,toml_config)?;
+
+    let mut mc = m.callbacks(MsgData::new(store,history,secrets,auth,policy,&m));
+    mc.data.query_window = query_window;
+    mc.data.untimed_queries = untimed_queries;
+    mc.data.sandbox = sandbox;
+    mc.data.psk_file = psk_file;
+    mc.data.toml = toml.clone();
     let t_cfg = mc.data.cfg.clone();
     let t_plugins = mc.data.plugins.clone();
+    let t_history = mc.data.history.clone();
 
     // keepalive strategy for moid is to publish an "I'm alive!" message occaisionally
     let alive_interval = geti_or(toml_config,"alive_interval",60)? as u64;
@@ -508,7 +1299,8 @@ fn run() -> BoxResult<()> {
         let mut ping_timeout = 0;
         loop {
             thread::sleep(Duration::from_secs(1));
-            let cfg = lock!(t_cfg);
+            let mut cfg = lock!(t_cfg);
+            expire_keys(&mut cfg,&mut lock!(t_history));
             if lock!(thread_timeout).timed_out() {
                error!("processing operation took too long - restarting");
                process::exit(1);
@@ -517,7 +1309,12 @@ fn run() -> BoxResult<()> {
             if secs >= ping_timeout {
                 secs = 0;
                 let plugins = lock!(t_plugins);
-                let res = populate_result_array(&cfg,&plugins, &alive_vars).unwrap().to_string();
+                let mut res = populate_result_array(&cfg,&plugins, &alive_vars).unwrap();
+                // so that everyone knows not to expect much of us
+                if in_maintenance(&cfg) {
+                    res.push(object!{"maintenance" => true}).unwrap();
+                }
+                let res = res.to_string();
                 if let Err(__) = thread_m.publish(ALIVE_TOPIC,res.as_bytes(),1,false) {
                     count += 1;
                 }
@@ -540,6 +1337,20 @@ fn run() -> BoxResult<()> {
         }
     });
 
+    // connecting again with a clean session loses our subscriptions
+    mc.on_connect(|mdata,rc| {
+        if rc != 0 {
+            return;
+        }
+        let me = format!("{}/{}",QUERY_TOPIC,lock!(mdata.cfg).addr());
+        for topic in &[QUERY_TOPIC,&me,QUIT_TOPIC] {
+            if let Err(e) = m.subscribe(topic,1) {
+                error!("resubscribing to {}: {}",topic,e);
+            }
+        }
+        info!("reconnected");
+    });
+
     mc.on_message(|mdata,msg| {
         // TODO error handling is still a mess
         // TODO RETRYING
@@ -616,7 +1427,10 @@ fn run() -> BoxResult<()> {
         }
 
         if quit.matches(&msg) {
-            m.disconnect().unwrap();
+            match check_quit(mdata,msg.text()) {
+                Ok(()) => m.disconnect().unwrap(),
+                Err(e) => warn!("ignoring {}: {}",QUIT_TOPIC,e)
+            }
         }
     });
 
@@ -0,0 +1,121 @@
+// What queries may do on this device, from the [policy] section of the
+// moid config. Operators who sign their queries can have their own rules
+// in [policy.operators.NAME]; anything they don't say comes from [policy].
+use moi::*;
+use moi::toml_utils::*;
+use toml;
+use std::io;
+use std::path::{Path,PathBuf,Component};
+use std::collections::HashMap;
+
+#[derive(Default)]
+struct Rules {
+    verbs: Option<Vec<String>>,
+    commands: Option<Vec<String>>,
+    destinations: Option<Vec<PathBuf>>,
+}
+
+fn strings(t: &toml::Value, key: &str) -> BoxResult<Option<Vec<String>>> {
+    Ok(match t.get(key) {
+        Some(v) => Some(toml_strings(v.as_array().or_then_err(|| format!("policy: '{}' must be array",key))?)?),
+        None => None
+    })
+}
+
+impl Rules {
+    fn new(t: &toml::Value) -> BoxResult<Rules> {
+        Ok(Rules {
+            verbs: strings(t,"verbs")?,
+            commands: strings(t,"commands")?,
+            destinations: strings(t,"destinations")?.map(|v| v.into_iter().map(PathBuf::from).collect()),
+        })
+    }
+}
+
+// so that 'systemctl restart *' does not allow 'systemctl restart x; rm -rf /'
+const SHELL_SPECIAL: &str = ";&|`$<>()\\\n";
+
+// '*' matches any sequence of characters, except those special to the shell
+fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.find('*') {
+        None => pattern == text,
+        Some(idx) => {
+            let (head,rest) = (&pattern[..idx], &pattern[idx+1..]);
+            if ! text.starts_with(head) {
+                return false;
+            }
+            let text = &text[head.len()..];
+            let end = text.find(|c| SHELL_SPECIAL.contains(c)).unwrap_or(text.len());
+            (0..end+1).filter(|&i| text.is_char_boundary(i))
+                .any(|i| glob_match(rest,&text[i..]))
+        }
+    }
+}
+
+fn deny(operator: Option<&String>, what: String) -> io::Error {
+    let msg = format!("policy: {} not allowed",what);
+    warn!("denied {}: {}",operator.map(|s| s.as_str()).unwrap_or("<unsigned>"),msg);
+    io_error(&msg)
+}
+
+pub struct Policy {
+    rules: Rules,
+    operators: HashMap<String,Rules>,
+}
+
+impl Policy {
+    pub fn new(t: Option<&toml::Value>) -> BoxResult<Policy> {
+        let mut res = Policy {
+            rules: Rules::default(),
+            operators: HashMap::new(),
+        };
+        if let Some(t) = t {
+            res.rules = Rules::new(t)?;
+            if let Some(ops) = t.get("operators") {
+                let ops = ops.as_table().or_err("policy: 'operators' must be a table")?;
+                for (name,rules) in ops.iter() {
+                    res.operators.insert(name.clone(),Rules::new(rules)?);
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    // the operator's rule if there is one, otherwise the general rule
+    fn rule<'a,T,F>(&'a self, operator: Option<&String>, get: F) -> Option<&'a T>
+    where F: Fn(&'a Rules) -> &'a Option<T> {
+        operator.and_then(|op| self.operators.get(op))
+            .and_then(|r| get(r).as_ref())
+            .or_else(|| get(&self.rules).as_ref())
+    }
+
+    pub fn check_verb(&self, operator: Option<&String>, verb: &str) -> io::Result<()> {
+        if let Some(verbs) = self.rule(operator,|r| &r.verbs) {
+            if ! verbs.iter().any(|v| v == verb) {
+                return Err(deny(operator,format!("verb '{}'",verb)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_command(&self, operator: Option<&String>, cmd: &str) -> io::Result<()> {
+        if let Some(commands) = self.rule(operator,|r| &r.commands) {
+            // (a lone '*' allows anything at all)
+            if ! commands.iter().any(|p| p == "*" || glob_match(p,cmd)) {
+                return Err(deny(operator,format!("command '{}'",cmd)));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_destination(&self, operator: Option<&String>, path: &Path) -> io::Result<()> {
+        if let Some(roots) = self.rule(operator,|r| &r.destinations) {
+            // '..' could climb out of any root
+            let climbs = path.components().any(|c| c == Component::ParentDir);
+            if climbs || ! roots.iter().any(|root| path.starts_with(root)) {
+                return Err(deny(operator,format!("path '{}'",path.display())));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+// Secret values are kept encrypted in the store, using a key which
+// never leaves this device. They are only ever decrypted to be passed
+// on to commands as environment variables.
+use moi::*;
+use json::JsonValue;
+use chacha20poly1305::{ChaCha20Poly1305,Key,Nonce};
+use chacha20poly1305::aead::{Aead,KeyInit};
+use std::os::unix::fs::OpenOptionsExt;
+use std::io::prelude::*;
+use std::fs;
+use std::path::Path;
+
+// what clients see instead of the value
+pub const REDACTED: &str = "<secret>";
+
+// secrets are stored as {"$secret":"NONCE+CIPHERTEXT"} in hex
+const SECRET_FIELD: &str = "$secret";
+const NONCE_SIZE: usize = 12;
+
+pub fn is_secret(val: &JsonValue) -> bool {
+    val.is_object() && val[SECRET_FIELD].is_string()
+}
+
+// copy of a value with any secrets inside it replaced by a marker
+pub fn redact(val: &JsonValue) -> JsonValue {
+    if is_secret(val) {
+        JsonValue::from(REDACTED)
+    } else
+    if val.is_object() {
+        let mut res = JsonValue::new_object();
+        for (k,v) in val.entries() {
+            res[k] = redact(v);
+        }
+        res
+    } else
+    if val.is_array() {
+        JsonValue::Array(val.members().map(redact).collect())
+    } else {
+        val.clone()
+    }
+}
+
+// is this dotted key a secret, or inside one?
+pub fn within_secret(cfg: &Config, key: &str) -> bool {
+    let mut path = String::new();
+    for part in key.split('.') {
+        if ! path.is_empty() {
+            path.push('.');
+        }
+        path.push_str(part);
+        if is_secret(&cfg.get_or(&path,JsonValue::Null)) {
+            return true;
+        }
+    }
+    false
+}
+
+// all secrets in the store, as (dotted key, encrypted value)
+pub fn collect_secrets(obj: &JsonValue, prefix: &str, res: &mut Vec<(String,JsonValue)>) {
+    for (k,v) in obj.entries() {
+        let key = if prefix.is_empty() {k.to_string()} else {format!("{}.{}",prefix,k)};
+        if is_secret(v) {
+            res.push((key,v.clone()));
+        } else
+        if v.is_object() {
+            collect_secrets(v,&key,res);
+        }
+    }
+}
+
+pub struct Secrets {
+    cipher: ChaCha20Poly1305,
+}
+
+impl Secrets {
+    // the key file is created on first use, and only moid can read it
+    pub fn new(keyfile: &Path) -> BoxResult<Secrets> {
+        if ! keyfile.exists() {
+            let mut f = fs::OpenOptions::new()
+                .create_new(true).write(true).mode(0o600)
+                .open(keyfile)?;
+            f.write_all(to_hex(&random_bytes(32)?).as_bytes())?;
+            info!("created secret key {}",keyfile.display());
+        }
+        let key = from_hex(read_to_string(keyfile)?.trim())
+            .map_err(|e| io_error(&format!("secret key {}: {}",keyfile.display(),e)))?;
+        (key.len() == 32).or_then_err(|| format!("secret key {} must be 32 bytes",keyfile.display()))?;
+        Ok(Secrets {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    pub fn encrypt(&self, plain: &str) -> BoxResult<JsonValue> {
+        let mut data = random_bytes(NONCE_SIZE)?;
+        let encrypted = self.cipher.encrypt(Nonce::from_slice(&data),plain.as_bytes())
+            .map_err(|_| io_error("cannot encrypt secret"))?;
+        data.extend(encrypted);
+        Ok(object!{SECRET_FIELD => to_hex(&data)})
+    }
+
+    pub fn decrypt(&self, val: &JsonValue) -> BoxResult<String> {
+        let data = from_hex(val[SECRET_FIELD].as_str().or_err("not a secret")?)?;
+        (data.len() > NONCE_SIZE).or_err("secret too short")?;
+        let (nonce,encrypted) = data.split_at(NONCE_SIZE);
+        let plain = self.cipher.decrypt(Nonce::from_slice(nonce),encrypted)
+            .map_err(|_| io_error("cannot decrypt secret - wrong key?"))?;
+        Ok(String::from_utf8(plain)?)
+    }
+
+    // secrets as environment variables for commands, e.g. db.password
+    // becomes MOI_SECRET_DB_PASSWORD
+    pub fn environment(&self, cfg: &Config) -> Vec<(String,String)> {
+        let mut secrets = Vec::new();
+        for (k,v) in cfg.values.iter() {
+            if is_secret(v) {
+                secrets.push((k.clone(),v.clone()));
+            } else
+            if v.is_object() {
+                collect_secrets(v,k,&mut secrets);
+            }
+        }
+        let mut res = Vec::new();
+        for (key,val) in secrets {
+            let name: String = key.chars()
+                .map(|c| if c.is_ascii_alphanumeric() {c.to_ascii_uppercase()} else {'_'})
+                .collect();
+            match self.decrypt(&val) {
+                Ok(plain) => res.push((format!("MOI_SECRET_{}",name),plain)),
+                Err(e) => error!("secret {}: {}",key,e),
+            }
+        }
+        res
+    }
+}
@@ -1,20 +1,26 @@
 // Shared code between moi (the cli driver) and moid (the daemon)
 // Mostly manages a convenient JSON store
-extern crate json;
+#[macro_use] extern crate json;
 extern crate toml;
 extern crate mosquitto_client;
 extern crate get_if_addrs;
+extern crate libc;
+extern crate hmac;
+extern crate sha2;
+extern crate ed25519_dalek;
 #[macro_use] extern crate log;
 extern crate time as timec;
 
 pub mod logging;
 pub mod toml_utils;
 pub mod timeout;
+pub mod auth;
 use toml_utils::*;
 
 use std::path::{Path,PathBuf};
 use std::io;
 use std::io::prelude::*;
+use std::fs;
 use std::fs::File;
 use std::time;
 use std::process;
@@ -94,6 +100,29 @@ pub fn current_time_as_secs() -> i64 {
     now.duration_since(time::UNIX_EPOCH).unwrap().as_secs() as i64
 }
 
+// local time, as used in logs
+pub fn secs_as_str(secs: i64) -> String {
+    let t = timec::at(timec::Timespec::new(secs,0));
+    timec::strftime("%Y-%m-%d %H:%M:%S",&t).unwrap()
+}
+
+pub fn random_bytes(n: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; n];
+    File::open("/dev/urandom")?.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}",b)).collect()
+}
+
+pub fn from_hex(s: &str) -> io::Result<Vec<u8>> {
+    (s.len() % 2 == 0 && s.is_ascii()).or_err("bad hex string")?;
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i+2],16).map_err(|_| io_error("bad hex string")))
+        .collect()
+}
+
 // you would think that the stdlib would actually provide
 // a method to do this...
 pub fn duration_as_millis(d: time::Duration) -> f64 {
@@ -105,12 +134,13 @@ fn lossy_str(bytes: &[u8]) -> String {
 }
 
 // pasop: will blow up if pwd does not exist!
-pub fn run_shell_command(cmd: &str, pwd: Option<&Path>) -> (i32,String,String) {
+pub fn run_shell_command(cmd: &str, pwd: Option<&Path>, env: &[(String,String)]) -> (i32,String,String) {
     let mut b = process::Command::new("/bin/sh");
     b.arg("-c").arg(cmd);
     if let Some(pwd) = pwd {
         b.current_dir(pwd);
     }
+    b.envs(env.iter().cloned());
     let o = b.output().expect("failed to execute shell"); // <--- should we fail here, hm? LOGGING...
 
     // useful to pass on killed-by-signal info?
@@ -120,12 +150,13 @@ pub fn run_shell_command(cmd: &str, pwd: Option<&Path>) -> (i32,String,String) {
     (code, stdout, stderr)
 }
 
-pub fn spawn_shell_command(cmd: &str, pwd: Option<&Path>) -> process::Child {
+pub fn spawn_shell_command(cmd: &str, pwd: Option<&Path>, env: &[(String,String)]) -> process::Child {
     let mut b = process::Command::new("/bin/sh");
     b.arg("-c").arg(cmd);
     if let Some(pwd) = pwd {
         b.current_dir(pwd);
     }
+    b.envs(env.iter().cloned());
     let c = b.spawn().expect("failed to spawn");
     c
 }
@@ -238,28 +269,81 @@ pub struct FilePending {
     pub filename: String,
     pub dest: PathBuf,
     pub perms: Option<u32>,
-    pub hash: Option<String>,
+    pub hash: Option<String>, // MD5, from older moi
+    pub sha256: Option<String>,
 }
 
+// what the [schema] section of the moid config says about a key.
+// For arrays, 'allowed' restricts the elements
+#[derive(Debug)]
+pub struct KeySchema {
+    pub kind: Option<String>,
+    pub default: Option<JsonValue>,
+    pub allowed: Vec<JsonValue>,
+}
+
+// told about the key, old and new value when a value changes
+pub type ChangeHook = Box<Fn(&str,&JsonValue,&JsonValue) + Send>;
+
 pub struct Config {
     pub values: HashMap<String,JsonValue>,
     pub file: PathBuf,
     pub pending_file: Option<FilePending>,
+    // keys (and their subkeys) which queries may not modify
+    pub readonly_keys: Vec<String>,
+    pub readonly_prefixes: Vec<String>,
+    // how many rotated copies of the store to keep
+    pub backups: usize,
+    pub on_change: Option<ChangeHook>,
+    pub schema: HashMap<String,KeySchema>,
 }
 
 use std::env;
 
+// store.json.1, store.json.2, ...
+fn numbered_path(file: &Path, suffix: &str) -> PathBuf {
+    PathBuf::from(format!("{}.{}",file.display(),suffix))
+}
+
+fn read_store(file: &Path) -> BoxResult<JsonValue> {
+    let s = read_to_string(file)?;
+    let doc = json::parse(&s)
+        .map_err(|e| io_error(&format!("json: {}",e)))?;
+    doc.is_object().or_then_err(|| format!("store {} is not a JSON object",file.display()))?;
+    Ok(doc)
+}
+
 impl Config {
 
     pub fn new_from_file(cfg: &toml::Value, file: &Path) -> BoxResult<Config> {
 
-        // initially the store may not exist - this is fine.
-        if ! file.exists() {
-            write_all(file,"{}\n")?;
-        }
-        let s = read_to_string(file)?;
-        let doc = json::parse(&s)
-            .map_err(|e| io_error(&format!("json: {}",e)))?;
+        let backups = geti_or(cfg,"store_backups",3)? as usize;
+        let doc = match read_store(file) {
+            Ok(doc) => doc,
+            Err(e) => {
+                // a power cut may have left us with a broken store -
+                // in which case the newest readable backup is used
+                let backup = (1..backups+1)
+                    .map(|i| numbered_path(file,&i.to_string()))
+                    .filter_map(|p| read_store(&p).ok().map(|doc| (p,doc)))
+                    .next();
+                if let Some((path,doc)) = backup {
+                    warn!("store {} unreadable ({}): using backup {}",file.display(),e,path.display());
+                    if file.exists() {
+                        fs::rename(file,numbered_path(file,"corrupt"))?;
+                    }
+                    fs::copy(&path,file)?;
+                    doc
+                } else
+                if ! file.exists() {
+                    // initially the store may not exist - this is fine.
+                    write_all(file,"{}\n")?;
+                    JsonValue::new_object()
+                } else {
+                    return Err(e);
+                }
+            }
+        };
 
         let mut map = HashMap::new();
         for (k,v) in doc.entries() {
@@ -270,6 +354,11 @@ impl Config {
             values: map,
             file: file.into(),
             pending_file: None,
+            readonly_keys: Vec::new(),
+            readonly_prefixes: Vec::new(),
+            backups: backups,
+            on_change: None,
+            schema: HashMap::new(),
         };
 
         config.insert_into("addr",gets_or_then(cfg,"addr",|| {
@@ -280,7 +369,7 @@ impl Config {
         })?);
 
         config.insert_into("name",gets_or_then(cfg,"name",|| {
-            let (_,name,_) = run_shell_command("hostname",None);
+            let (_,name,_) = run_shell_command("hostname",None,&[]);
             name
         })?);
 
@@ -294,7 +383,7 @@ impl Config {
         self.values.insert(key.into(),val.into());
     }
     
-    fn json_type(val: &JsonValue) -> &str {
+    pub fn json_type(val: &JsonValue) -> &str {
         use JsonValue::*;
         match *val {
             Null => "null",
@@ -306,50 +395,188 @@ impl Config {
         }
     }
     
+    // plain string values are coerced to the type declared in the schema,
+    // or else the type of an existing number or boolean; otherwise the types must agree
     fn assert_json_type(&self, key: &str, val: &JsonValue) -> io::Result<JsonValue> {
-        if let Some(existing_val) = self.values.get(key) {
-            let et = Config::json_type(existing_val);
-            let vt = Config::json_type(val);
-            if et == "number" && vt == "string" {
-                let s = val.as_str().unwrap();
-                let num: f64 = s.parse()
-                    .map_err(|e| io_error(&format!("cannot parse {:} as number: {}",s,e)))?;
-                return Ok(num.into());
+        let et = if let Some(kind) = self.schema.get(key).and_then(|s| s.kind.as_ref()) {
+            kind.as_str()
+        } else
+        if let Ok(existing_val) = self.get(key) {
+            Config::json_type(existing_val)
+        } else {
+            return Ok(val.clone());
+        };
+        let vt = Config::json_type(val);
+        if et == "number" && vt == "string" {
+            let s = val.as_str().unwrap();
+            let num: f64 = s.parse()
+                .map_err(|e| io_error(&format!("key '{}' expects a number: cannot parse {:?}: {}",key,s,e)))?;
+            Ok(num.into())
+        } else
+        if et == "boolean" && vt == "string" {
+            let s = val.as_str().unwrap();
+            let b: bool = s.parse()
+                .map_err(|_| io_error(&format!("key '{}' expects a boolean: cannot parse {:?}",key,s)))?;
+            Ok(b.into())
+        } else {
+            (et == vt).or_then_err(|| format!("key '{}' expects a {} value, not {} {}",key,et,vt,val))?;
+            Ok(val.clone())
+        }
+    }
+
+    // the schema may only allow some values (or array elements)
+    fn is_allowed(&self, key: &str, val: &JsonValue) -> bool {
+        match self.schema.get(key) {
+            Some(schema) if ! schema.allowed.is_empty() => if val.is_array() {
+                val.members().all(|v| schema.allowed.contains(v))
             } else {
-                (et == vt).or_then_err(|| format!("key type is {}, not {}!",et,vt))?;
+                schema.allowed.contains(val)
+            },
+            _ => true
+        }
+    }
+
+    fn allowed_values(&self, key: &str) -> JsonValue {
+        JsonValue::from(self.schema[key].allowed.clone())
+    }
+
+    fn check_allowed(&self, key: &str, val: &JsonValue) -> io::Result<()> {
+        self.is_allowed(key,val)
+            .or_then_err(|| format!("key '{}' does not allow {}: must be one of {}",key,val,self.allowed_values(key)))
+    }
+
+    // problems with the existing values of keys in the schema
+    pub fn validate(&self) -> JsonValue {
+        let mut res = JsonValue::new_array();
+        let mut keys: Vec<_> = self.schema.keys().collect();
+        keys.sort();
+        for key in keys {
+            let val = match self.get_stored(key) {
+                Ok(val) => val,
+                Err(_) => continue // unset keys have their default
+            };
+            let vt = Config::json_type(val);
+            let problem = match self.schema[key].kind {
+                Some(ref kind) if kind != vt => format!("expects a {} value, not {}",kind,vt),
+                _ if ! self.is_allowed(key,val) => format!("must be one of {}",self.allowed_values(key)),
+                _ => continue
+            };
+            res.push(object!{"key" => key.as_str(), "value" => val.clone(), "problem" => problem}).unwrap();
+        }
+        res
+    }
+
+    // dotted keys like 'net.eth0.mtu' walk into nested objects,
+    // which are created as needed
+    fn get_mut_or_create(&mut self, key: &str) -> io::Result<&mut JsonValue> {
+        let mut iter = key.split('.');
+        let base = iter.next().unwrap();
+        let mut obj = self.values.entry(base.into()).or_insert(JsonValue::Null);
+        for field in iter {
+            if obj.is_null() {
+                *obj = JsonValue::new_object();
             }
+            obj.is_object().or_then_err(|| format!("'{}' is not an object in '{}'",field,key))?;
+            obj = &mut obj[field];
+        }
+        Ok(obj)
+    }
+
+    // 'groups' is special: moi manages group membership element-wise
+    fn check_writeable(&self, key: &str, elementwise: bool) -> io::Result<()> {
+        if elementwise && key == "groups" {
+            return Ok(());
         }
-        Ok(val.clone())
+        let protected = self.readonly_keys.iter()
+            .any(|k| key == k || (key.starts_with(k.as_str()) && key[k.len()..].starts_with('.')));
+        let prefixed = self.readonly_prefixes.iter().any(|p| key.starts_with(p.as_str()));
+        (! protected && ! prefixed).or_then_err(|| format!("key '{}' is read-only on this device",key))
     }
 
     // setting a key to null clears it....
+    // only worth keeping the old value if someone wants to know
+    fn old_value(&self, key: &str) -> Option<JsonValue> {
+        self.on_change.as_ref().map(|_| self.get_or(key,JsonValue::Null))
+    }
+
+    fn notify_change(&self, key: &str, old: Option<JsonValue>) {
+        if let (Some(hook),Some(old)) = (self.on_change.as_ref(),old) {
+            let new = self.get_or(key,JsonValue::Null);
+            if old != new {
+                hook(key,&old,&new);
+            }
+        }
+    }
+
+    // the value insert would store, without storing it
+    pub fn check_insert(&self, key: &str, val: &JsonValue) -> io::Result<JsonValue> {
+        self.check_writeable(key,false)?;
+        if val.is_null() {
+            return Ok(JsonValue::Null);
+        }
+        let val = self.assert_json_type(key,val)?;
+        self.check_allowed(key,&val)?;
+        Ok(val)
+    }
+
     pub fn insert(&mut self, key: &str, val: &JsonValue) -> io::Result<()> {
-        if val == &JsonValue::Null {
-            self.values.remove(key);
+        let val = self.check_insert(key,val)?;
+        let old = self.old_value(key);
+        if val.is_null() {
+            if let Some(idx) = key.rfind('.') {
+                let (parent,field) = (&key[0..idx], &key[idx+1..]);
+                // nothing to clear if the parent object isn't there
+                if self.get(parent).map(|p| p.is_object()).unwrap_or(false) {
+                    self.get_mut_or_create(parent)?.remove(field);
+                }
+            } else {
+                self.values.remove(key);
+            }
         } else {
-            let val = self.assert_json_type(key,val)?;
-            self.values.insert(key.into(), val);
+            *self.get_mut_or_create(key)? = val;
         }
+        self.notify_change(key,old);
         Ok(())
     }
 
     // the idea is NOT to add values if already present in the array
     // Must ask explicitly to remove tho
+    pub fn check_insert_array(&self, key: &str, val: &JsonValue, remove: bool) -> io::Result<()> {
+        self.check_writeable(key,true)?;
+        if let Some(kind) = self.schema.get(key).and_then(|s| s.kind.as_ref()) {
+            (kind == "array").or_then_err(|| format!("key '{}' expects a {} value, not array",key,kind))?;
+        }
+        if ! remove {
+            self.check_allowed(key,val)?;
+        }
+        if let Ok(existing) = self.get_stored(key) {
+            (existing.is_null() || existing.is_array()).or_then_err(|| format!("{} is not array-valued",key))?;
+        }
+        Ok(())
+    }
+
     pub fn insert_array(&mut self, key: &str, val: &JsonValue, remove: bool) -> io::Result<()> {
-        let arr = self.values.entry(key.into())
-            .or_insert_with(|| JsonValue::new_array());
-        (arr.is_array()).or_then_err(|| format!("{} is not array-valued",key))?;
-
-        let present = arr.members().any(|v| v == val);
-        if remove {
-            if present {
-                let pos = arr.members().position(|v| v == val).unwrap();
-                arr.array_remove(pos);
+        self.check_insert_array(key,val,remove)?;
+        let old = self.old_value(key);
+        {
+            let arr = self.get_mut_or_create(key)?;
+            if arr.is_null() {
+                *arr = JsonValue::new_array();
+            }
+            (arr.is_array()).or_then_err(|| format!("{} is not array-valued",key))?;
+
+            let present = arr.members().any(|v| v == val);
+            if remove {
+                if present {
+                    let pos = arr.members().position(|v| v == val).unwrap();
+                    arr.array_remove(pos);
+                }
+            } else
+            if ! present {
+                arr.push(val.clone()).unwrap();
             }
-        } else
-        if ! present {
-            arr.push(val.clone()).unwrap();
         }
+        self.notify_change(key,old);
         Ok(())
     }
 
@@ -360,12 +587,80 @@ impl Config {
         for (k,v) in self.values.iter() {
             doc[k] = v.clone();
         }
-        let mut f = File::create(&self.file)?;
         let jout = json::stringify_pretty(doc,2);
-        f.write_all(jout.as_bytes())
+        // write and sync a temporary file, which then replaces the store:
+        // so the store is always either the old or the new version
+        let tmp = numbered_path(&self.file,"tmp");
+        {
+            let mut f = File::create(&tmp)
+                .map_err(|e| io_error(&format!("writing file {}: {}",tmp.display(),e)))?;
+            f.write_all(jout.as_bytes())?;
+            f.sync_all()?;
+        }
+        self.rotate_backups()?;
+        fs::rename(&tmp,&self.file)?;
+        // and make the rename itself durable
+        if let Some(dir) = self.file.parent() {
+            if let Ok(d) = File::open(dir) {
+                let _ = d.sync_all();
+            }
+        }
+        Ok(())
+    }
+
+    // advisory lock on store.json.lock, held until the returned file is dropped.
+    // Only needed when several processes share a store, like moi invocations
+    pub fn lock(&self) -> io::Result<File> {
+        use std::os::unix::io::AsRawFd;
+        let lockfile = numbered_path(&self.file,"lock");
+        let f = fs::OpenOptions::new().create(true).write(true).open(&lockfile)
+            .map_err(|e| io_error(&format!("lock file {}: {}",lockfile.display(),e)))?;
+        if unsafe { libc::flock(f.as_raw_fd(),libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(f)
+    }
+
+    // read-modify-write: under the lock, re-read the store (others may have
+    // changed or removed keys since we loaded it), apply our change, and write it out
+    pub fn update<F: FnOnce(&mut Config)>(&mut self, change: F) -> BoxResult<()> {
+        let _lock = self.lock()?;
+        if self.file.exists() {
+            let doc = read_store(&self.file)?;
+            self.values.clear();
+            for (k,v) in doc.entries() {
+                self.values.insert(k.to_string(),v.clone());
+            }
+        }
+        change(self);
+        self.write()?;
+        Ok(())
     }
 
+    // store.json.1 is the newest backup
+    fn rotate_backups(&self) -> io::Result<()> {
+        if self.backups == 0 || ! self.file.exists() {
+            return Ok(());
+        }
+        for i in (1..self.backups).rev() {
+            let older = numbered_path(&self.file,&i.to_string());
+            if older.exists() {
+                fs::rename(&older,numbered_path(&self.file,&(i+1).to_string()))?;
+            }
+        }
+        fs::copy(&self.file,numbered_path(&self.file,"1"))?;
+        Ok(())
+    }
+
+    // unset keys may have a default in the schema
     pub fn get(&self,key: &str) -> io::Result<&JsonValue> {
+        match self.get_stored(key) {
+            Ok(val) => Ok(val),
+            Err(e) => self.schema.get(key).and_then(|s| s.default.as_ref()).ok_or(e)
+        }
+    }
+
+    fn get_stored(&self,key: &str) -> io::Result<&JsonValue> {
         let mut iter = key.split('.');
         let base = iter.next().unwrap();
         let mut obj = self.values.get(base)
@@ -373,6 +668,8 @@ impl Config {
         for field in iter {
             obj = &obj[field]
         }
+        // a missing subfield is just as unknown as a missing key
+        (! obj.is_null()).or_then_err(|| format!("unknown key '{}'",key))?;
         Ok(obj)
     }
 
@@ -420,15 +717,43 @@ impl Config {
     }
 }
 
+// the TLS-PSK key file, if there is a [tls_psk] section
+pub fn tls_psk_file(toml: &toml::Value, path_def: &Path) -> BoxResult<Option<PathBuf>> {
+    use toml_utils::*;
+    Ok(if let Some(tls_psk) = toml.get("tls_psk") {
+        let path: PathBuf = if let Some(path) = gets_opt(tls_psk,"path")? {
+            path.into()
+        } else {
+            path_def.to_path_buf()
+        };
+        Some(path.join(gets(tls_psk,"psk_file")?))
+    } else {
+        None
+    })
+}
+
+// the identity and key, in the same format as psk_file in mosquitto.conf
+pub fn read_psk_file(psk_keyfile: &Path) -> BoxResult<(String,String)> {
+    let text = read_to_string(psk_keyfile)?.trim_right_matches('\n').to_string();
+    if let Some(idx) = text.find(':') {
+        Ok((text[0..idx].to_string(), text[idx+1..].to_string()))
+    } else {
+        err_io("psk key file is iden:bytes")
+    }
+}
+
 pub fn mosquitto_setup(name: &str, config: &toml::Value, toml: &toml::Value, path_def: PathBuf) -> BoxResult<mosquitto_client::Mosquitto> {
     use toml_utils::*;
+    // client ids must be unique, or the broker drops the older client
+    let name = gets_opt(config,"mqtt_client_id")?.unwrap_or(name);
+    info!("MQTT client id {}",name);
     let m = mosquitto_client::Mosquitto::new(name);
 
     if let Some(tls) = toml.get("tls") {
         let path: PathBuf = if let Some(path) = gets_opt(tls,"path")? {
             path.into()
         } else {
-            path_def
+            path_def.clone()
         };
         let cafile = path.join(gets(tls,"cafile")?);
         let certfile = path.join(gets(tls,"certfile")?);
@@ -437,29 +762,54 @@ pub fn mosquitto_setup(name: &str, config: &toml::Value, toml: &toml::Value, pat
         info!("TLS {:?} {:?} {:?} {:?}",cafile,certfile,keyfile,passphrase);
         m.tls_set(cafile,certfile,keyfile,passphrase)?;
     } else
-    if let Some(tls_psk) = toml.get("tls_psk") {
-        let path: PathBuf = if let Some(path) = gets_opt(tls_psk,"path")? {
-            path.into()
-        } else {
-            path_def
-        };
-        let psk_keyfile = path.join(gets(tls_psk,"psk_file")?);
-        let text = read_to_string(&psk_keyfile)?.trim_right_matches('\n').to_string();
-        let ciphers = gets_opt(tls_psk,"ciphers")?;
-        let (identity,key) = if let Some(idx) = text.find(':') {
-            (&text[0..idx], &text[idx+1..])
-        } else {
-            return err_io("psk key file is iden:bytes");
-        };
-        info!("TLS-PSK identity {:?} key {:?} ciphers {:?}",identity,key,ciphers);
-        m.tls_psk_set(key,identity,ciphers)?;
+    if let Some(psk_keyfile) = tls_psk_file(toml,&path_def)? {
+        let (identity,key) = read_psk_file(&psk_keyfile)?;
+        mosquitto_psk(&m,toml,&identity,&key)?;
     }
+    mosquitto_connect(&m,config)?;
+    Ok(m)
+}
 
-    {
-        let addr = gets_or(config,"mqtt_addr","127.0.0.1")?;
-        let port = geti_or(config,"mqtt_port",1883)? as u32;
-        info!("MQTT addr {} port {}",addr,port);
-        m.connect_wait(addr,port,geti_or(config,"mqtt_connect_wait",300)? as i32)?;
-    }
+// connect with a TLS-PSK identity and key which need not be in the key file,
+// so we can check that the broker knows them before anyone depends on them
+pub fn mosquitto_psk_setup(name: &str, config: &toml::Value, toml: &toml::Value, identity: &str, key: &str) -> BoxResult<mosquitto_client::Mosquitto> {
+    let m = mosquitto_client::Mosquitto::new(name);
+    mosquitto_psk(&m,toml,identity,key)?;
+    mosquitto_connect(&m,config)?;
     Ok(m)
 }
+
+// switch a connected client over to another TLS-PSK identity and key
+pub fn mosquitto_psk_reconnect(m: &mosquitto_client::Mosquitto, toml: &toml::Value, identity: &str, key: &str) -> BoxResult<()> {
+    mosquitto_psk(m,toml,identity,key)?;
+    m.reconnect()?;
+    Ok(())
+}
+
+fn mosquitto_psk(m: &mosquitto_client::Mosquitto, toml: &toml::Value, identity: &str, key: &str) -> BoxResult<()> {
+    use toml_utils::*;
+    let ciphers = match toml.get("tls_psk") {
+        Some(tls_psk) => gets_opt(tls_psk,"ciphers")?,
+        None => None
+    };
+    info!("TLS-PSK identity {:?} ciphers {:?}",identity,ciphers); // not the key!
+    m.tls_psk_set(key,identity,ciphers)?;
+    Ok(())
+}
+
+fn mosquitto_connect(m: &mosquitto_client::Mosquitto, config: &toml::Value) -> BoxResult<()> {
+    use toml_utils::*;
+    // TODO: username/password login. The mosquitto-client binding (0.1.4, and 0.1.5)
+    // has no mosquitto_username_pw_set, so this needs a change to the binding first.
+    // Until then, a config which asks for it must not quietly connect without it
+    for key in &["mqtt_user","mqtt_password","mqtt_password_file"] {
+        config.get(key).is_none()
+            .or_then_err(|| format!("{}: broker username/password login is not implemented yet",key))?;
+    }
+
+    let addr = gets_or(config,"mqtt_addr","127.0.0.1")?;
+    let port = geti_or(config,"mqtt_port",1883)? as u32;
+    info!("MQTT addr {} port {}",addr,port);
+    m.connect_wait(addr,port,geti_or(config,"mqtt_connect_wait",300)? as i32)?;
+    Ok(())
+}
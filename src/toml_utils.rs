@@ -65,3 +65,69 @@ pub fn toml_strings (t: &Vec<toml::Value>) -> BoxResult<Vec<String>> {
     t.into_iter().map(|s| as_string(s)).collect()
 }
 
+// a table like [filters] where all the values are strings
+pub fn toml_string_map (t: &toml::Value) -> BoxResult<HashMap<String,String>> {
+    let table = t.as_table().or_err("expected a table")?;
+    let mut res = HashMap::new();
+    for (k,v) in table.iter() {
+        let v = v.as_str().or_then_err(|| format!("value of '{}' is not a string",k))?;
+        res.insert(k.clone(),v.to_string());
+    }
+    Ok(res)
+}
+
+pub fn toml_to_json(v: &toml::Value) -> JsonValue {
+    use toml::Value::*;
+    match *v {
+        String(ref s) => s.as_str().into(),
+        Integer(i) => i.into(),
+        Float(x) => x.into(),
+        Boolean(b) => b.into(),
+        Datetime(ref d) => d.to_string().into(),
+        Array(ref arr) => JsonValue::Array(arr.iter().map(toml_to_json).collect()),
+        Table(ref t) => {
+            let mut res = JsonValue::new_object();
+            for (k,v) in t.iter() {
+                res[k.as_str()] = toml_to_json(v);
+            }
+            res
+        }
+    }
+}
+
+const SCHEMA_TYPES: &[&str] = &["string","number","boolean","array","object"];
+
+// a [schema] table, where [schema.net.mtu] and [schema."net.mtu"] both describe
+// the dotted key net.mtu
+pub fn toml_schema(t: &toml::Value, prefix: &str, res: &mut HashMap<String,KeySchema>) -> BoxResult<()> {
+    let table = t.as_table().or_err("schema: expected a table")?;
+    for (k,v) in table.iter() {
+        let key = if prefix.is_empty() {k.clone()} else {format!("{}.{}",prefix,k)};
+        let spec = v.as_table().or_then_err(|| format!("schema: '{}' must be a table",key))?;
+        if ! ["type","default","allowed"].iter().any(|f| spec.contains_key(*f)) {
+            toml_schema(v,&key,res)?;
+            continue;
+        }
+        let kind = gets_opt(v,"type")?.map(|s| s.to_string());
+        if let Some(ref kind) = kind {
+            SCHEMA_TYPES.contains(&kind.as_str())
+                .or_then_err(|| format!("schema: type of '{}' must be one of {}",key,SCHEMA_TYPES.join(",")))?;
+        }
+        let allowed = match v.get("allowed") {
+            Some(allowed) => allowed.as_array().or_then_err(|| format!("schema: 'allowed' of '{}' must be array",key))?
+                .iter().map(toml_to_json).collect(),
+            None => Vec::new()
+        };
+        let default = v.get("default").map(toml_to_json);
+        if let (Some(kind),Some(default)) = (kind.as_ref(),default.as_ref()) {
+            (Config::json_type(default) == kind)
+                .or_then_err(|| format!("schema: default of '{}' must be a {}",key,kind))?;
+        }
+        res.insert(key,KeySchema {
+            kind: kind,
+            default: default,
+            allowed: allowed,
+        });
+    }
+    Ok(())
+}